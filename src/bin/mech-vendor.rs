@@ -0,0 +1,62 @@
+// # Mech Vendor
+
+// Fetches the registry and every machine a program needs into a directory,
+// so the program can run offline with that directory as its machine
+// directory. Registries are used in the order they're given.
+//
+//   mech-vendor <program.mec> <directory> [--registry <url or path>]...
+
+extern crate mech_program;
+extern crate crossbeam_channel;
+
+use mech_program::*;
+use std::path::Path;
+use std::process::exit;
+
+fn usage() -> ! {
+  eprintln!("Usage: mech-vendor <program.mec> <directory> [--registry <url or path>]...");
+  exit(2);
+}
+
+fn main() {
+  let mut paths = vec![];
+  let mut registries = vec![];
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--registry" => registries.push(args.next().unwrap_or_else(|| usage())),
+      "-h" | "--help" => usage(),
+      _ => paths.push(arg),
+    }
+  }
+  if paths.len() != 2 {
+    usage();
+  }
+  if registries.is_empty() {
+    registries.push(DEFAULT_REGISTRY.to_string());
+  }
+
+  // Print progress as it comes
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let printer = std::thread::spawn(move || {
+    for message in incoming {
+      match message {
+        ClientMessage::String(message) => println!("{}", message),
+        _ => (),
+      }
+    }
+  });
+  let result = vendor_machines(Path::new(&paths[0]), &registries, Path::new(&paths[1]), Some(outgoing));
+  let _ = printer.join();
+  match result {
+    Ok(machines) => {
+      for machine in machines {
+        println!("{} v{}", machine.name, machine.version);
+      }
+    }
+    Err(err) => {
+      eprintln!("{}", format_errors(&vec![err]));
+      exit(1);
+    }
+  }
+}
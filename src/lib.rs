@@ -10,6 +10,9 @@ extern crate core;
 extern crate libloading;
 extern crate reqwest;
 extern crate indexmap;
+extern crate crc32fast;
+extern crate sha2;
+extern crate semver;
 
 #[macro_use]
 extern crate serde_derive;
@@ -34,7 +37,7 @@ extern crate websocket;
 use colored::*;
 use libloading::Library;
 use std::io::copy;
-use std::fs::{OpenOptions, File, canonicalize, create_dir};
+use std::fs::{OpenOptions, File, canonicalize, create_dir, create_dir_all};
 use std::path::{Path, PathBuf};
 use crossbeam_channel::Sender;
 use crossbeam_channel::Receiver;
@@ -44,13 +47,17 @@ use reqwest::StatusCode;
 
 pub mod program;
 pub mod persister;
+pub mod migration;
+pub mod machines;
 pub mod runloop;
 
 // ## Exported Modules
 
 pub use self::program::{Program};
-pub use self::runloop::{ProgramRunner, RunLoop, ClientMessage};
-pub use self::persister::{Persister};
+pub use self::migration::{Migration, Replay};
+pub use self::machines::{MACHINE_DIR_VAR, DEFAULT_REGISTRY, DEFAULT_REGISTRY_TTL, registry_age, VersionChange, version_changes, default_machine_dir, resolve_machine_dir, vendor_machines, machine_library, machine_path, is_remote, local_path, machine_available, RegistryEntry, read_registry, merge_registry, search_registry, parse_version, select_version, Lockfile, LockLocation, program_lock_path, file_digest, verify_machine, CachedMachine, cached_machines, prune_machines, load_machine};
+pub use self::runloop::{ProgramRunner, RunLoop, ClientMessage, ControlMessage, QueueOverflow};
+pub use self::persister::{Persister, PersisterMessage, PersisterOptions, PersistBackend, FileBackend, MemoryBackend, PersistencePolicy, LogReader, PointInTime, LogEnd, LogPosition, FsyncPolicy, Codec};
 
 pub fn format_errors(errors: &Vec<MechError>) -> String {
   let mut formatted_errors = "".to_string();
@@ -77,59 +84,85 @@ pub fn format_errors(errors: &Vec<MechError>) -> String {
   formatted_errors
 }
 
-pub fn download_machine(machine_name: &str, name: &str, path_str: &str, ver: &str, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Library,MechError> {
-  create_dir("machines");
+pub fn download_machine(machine_dir: &Path, machine_name: &str, entry: &RegistryEntry, offline: bool, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Library,MechError> {
+  let machine_file_path = cache_machine(machine_dir, machine_name, entry, offline, outgoing)?;
+  let message = format!("Can't load library {:?}", machine_file_path);
+  match unsafe{Library::new(machine_file_path)} {
+    Ok(machine) => Ok(machine),
+    Err(err) => Err(MechError{msg: "".to_string(), id: 1273, kind: MechErrorKind::GenericError(format!("{:?}",message))}),
+  }
+}
 
-  let machine_file_path = format!("machines/{}",machine_name);
-  {
-    let path = Path::new(path_str);
-    // Download from the web
-    if path.to_str().unwrap().starts_with("https") {
-      match outgoing {
-        Some(ref sender) => {sender.send(ClientMessage::String(format!("{} {} v{}", "[Downloading]".truecolor(153,221,85), name, ver)));}
-        None => (),
-      }
-      let machine_url = format!("{}/{}", path_str, machine_name);
-      match reqwest::get(machine_url.as_str()) {
-        Ok(mut response) => {
-          match response.status() {
-            StatusCode::OK => {
-              let mut dest = File::create(machine_file_path.clone())?;
-              copy(&mut response, &mut dest)?;
-            },
-            _ => {
-              match outgoing {
-                Some(sender) => {sender.send(ClientMessage::String(format!("{} Failed to download {} v{}", "[Error]".bright_red(), name, ver)));}
-                None => (),
-              }
-            },
-          }
-        }
-        Err(_) => {
-          match outgoing {
-            Some(sender) => {sender.send(ClientMessage::String(format!("{} Failed to download {} v{}", "[Error]".bright_red(), name, ver)));}
-            None => (),
-          }
+// Machines are fetched into a partial file next to where they're cached, and
+// only moved into place once they're complete and match their digest. An
+// interrupted download never leaves a library behind that we'd load later.
+// Mirrors are tried in order until one works, skipping remote ones when we're
+// offline.
+pub fn cache_machine(machine_dir: &Path, machine_name: &str, entry: &RegistryEntry, offline: bool, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<PathBuf,MechError> {
+  let (name, ver) = (&entry.name, &entry.version);
+  let machine_file_path = machine_path(machine_dir, name, ver, machine_name);
+  create_dir_all(machine_file_path.parent().unwrap())?;
+  let partial_path = machine_file_path.with_file_name(format!("{}.part", machine_name));
+  let mut first_err = None;
+  for url in entry.urls().filter(|url| !offline || !is_remote(url)) {
+    let fetched = fetch_machine(machine_name, entry, url, &partial_path, &outgoing)
+      .and_then(|_| verify_machine(&partial_path, entry))
+      .and_then(|_| Ok(std::fs::rename(&partial_path, &machine_file_path)?));
+    match fetched {
+      Ok(()) => return Ok(machine_file_path),
+      Err(err) => {
+        std::fs::remove_file(&partial_path);
+        match outgoing {
+          Some(ref sender) => {sender.send(ClientMessage::String(format!("{} Failed to download {} v{} from {}", "[Error]".bright_red(), name, ver, url)));}
+          None => (),
         }
+        first_err.get_or_insert(err);
       }
+    }
+  }
+  match first_err {
+    Some(err) => Err(err),
+    None => Err(MechError{msg: "".to_string(), id: 1281, kind: MechErrorKind::GenericError(format!("Offline, and {} v{} isn't at a local path", name, ver))}),
+  }
+}
 
-    // Load from a local directory
-    } else {
-      match outgoing {
-        Some(sender) => {sender.send(ClientMessage::String(format!("{} {} v{}", "[Loading]".truecolor(153,221,85), name, ver)));}
-        None => (),
+// Writes a machine's library to dest_path, from the web or a local directory
+fn fetch_machine(machine_name: &str, entry: &RegistryEntry, path_str: &str, dest_path: &Path, outgoing: &Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<(),MechError> {
+  let (name, ver) = (&entry.name, &entry.version);
+  let mut dest = File::create(dest_path)?;
+  // Download from the web
+  if is_remote(path_str) {
+    match outgoing {
+      Some(sender) => {sender.send(ClientMessage::String(format!("{} {} v{}", "[Downloading]".truecolor(153,221,85), name, ver)));}
+      None => (),
+    }
+    let machine_url = format!("{}/{}", path_str, machine_name);
+    let mut response = match reqwest::get(machine_url.as_str()) {
+      Ok(response) => response,
+      Err(err) => {return Err(MechError{msg: "".to_string(), id: 1276, kind: MechErrorKind::GenericError(format!("Can't download {}: {}", machine_url, err))});},
+    };
+    match response.status() {
+      StatusCode::OK => (),
+      status => {return Err(MechError{msg: "".to_string(), id: 1277, kind: MechErrorKind::GenericError(format!("Can't download {}: {}", machine_url, status))});},
+    }
+    let expected_len = response.content_length();
+    let received_len = copy(&mut response, &mut dest)?;
+    match expected_len {
+      Some(expected_len) if expected_len != received_len => {
+        return Err(MechError{msg: "".to_string(), id: 1278, kind: MechErrorKind::GenericError(format!("Download of {} stopped after {} of {} bytes", machine_url, received_len, expected_len))});
       }
-      let machine_path = format!("{}{}", path_str, machine_name);
-      let path = Path::new(&machine_path);
-      let mut dest = File::create(machine_file_path.clone())?;
-      let mut f = File::open(path)?;
-      copy(&mut f, &mut dest)?;
+      _ => (),
     }
+  // Load from a local directory
+  } else {
+    match outgoing {
+      Some(sender) => {sender.send(ClientMessage::String(format!("{} {} v{}", "[Loading]".truecolor(153,221,85), name, ver)));}
+      None => (),
+    }
+    let machine_path = local_path(&format!("{}{}", path_str, machine_name));
+    let mut f = File::open(&machine_path)?;
+    copy(&mut f, &mut dest)?;
   }
-  let machine_file_path = format!("machines/{}",machine_name);
-  let message = format!("Can't load library {:?}", machine_file_path);
-  match unsafe{Library::new(machine_file_path)} {
-    Ok(machine) => Ok(machine),
-    Err(err) => Err(MechError{msg: "".to_string(), id: 1273, kind: MechErrorKind::GenericError(format!("{:?}",message))}),
-  }
+  dest.sync_all()?;
+  Ok(())
 }
@@ -0,0 +1,477 @@
+// # Machines
+
+// Downloaded machines are cached one directory per version, so upgrading a
+// machine in the registry doesn't keep loading the old library:
+//
+//   <machine dir>/registry.mec
+//   <machine dir>/<name>/<version>/<library>
+//
+// Registry entries can carry a SHA-256 digest of the library. When they do,
+// the library is checked after it's downloaded and every time it's loaded.
+//
+// The registry can list several versions of a machine. Programs pick from
+// them with requirements like ">= 0.1.3, < 0.2" in a mech/dependencies table,
+// and otherwise get the highest version.
+//
+// Programs can list several registries, in order of precedence. Their
+// machines are merged: every version any of them lists is available, and when
+// more than one lists the same version, the first one's entry is used and the
+// others' URLs become its mirrors. A registry that can't be loaded is skipped,
+// and a machine that can't be downloaded is tried at its mirrors in order.
+//
+// Downloaded registries are cached, and fetched again once the cached copy is
+// older than the program's registry TTL. The cached file's modification time
+// is when it was fetched.
+
+// ## Prelude
+
+use mech_core::*;
+use mech_syntax::compiler::Compiler;
+use colored::*;
+use libloading::Library;
+use crossbeam_channel::Sender;
+use hashbrown::HashMap;
+use sha2::{Sha256, Digest};
+use semver::{Version, VersionReq};
+use std::fs::{self, File, create_dir_all};
+use std::io;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{download_machine, cache_machine};
+use super::program::Program;
+use super::runloop::ClientMessage;
+
+// ## Machine Directory
+
+// Overrides where downloaded machines are kept
+pub const MACHINE_DIR_VAR: &str = "MECH_MACHINE_DIR";
+
+// Where machines are kept unless a program says otherwise: $MECH_MACHINE_DIR
+// if it's set, or mech/machines in the user's cache directory. Without a
+// home directory we fall back to machines/ in the working directory.
+pub fn default_machine_dir() -> PathBuf {
+  resolve_machine_dir(&|name| std::env::var_os(name))
+}
+
+// default_machine_dir, with environment variables looked up by env
+pub fn resolve_machine_dir(env: &dyn Fn(&str) -> Option<OsString>) -> PathBuf {
+  let var = |name: &str| env(name).filter(|dir| !dir.is_empty()).map(PathBuf::from);
+  if let Some(dir) = var(MACHINE_DIR_VAR) {
+    return dir;
+  }
+  #[cfg(target_os = "windows")]
+  let cache_dir = var("LOCALAPPDATA");
+  #[cfg(target_os = "macos")]
+  let cache_dir = var("HOME").map(|home| home.join("Library").join("Caches"));
+  #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+  let cache_dir = var("XDG_CACHE_HOME").or_else(|| var("HOME").map(|home| home.join(".cache")));
+  match cache_dir {
+    Some(cache_dir) => cache_dir.join("mech").join("machines"),
+    None => PathBuf::from("machines"),
+  }
+}
+
+// The file name of a machine's library on this platform
+pub fn machine_library(name: &str) -> String {
+  let underscore_name = name.replace("-","_");
+  #[cfg(target_os = "macos")]
+  let library = format!("libmech_{}.dylib", underscore_name);
+  #[cfg(target_os = "linux")]
+  let library = format!("libmech_{}.so", underscore_name);
+  #[cfg(target_os = "windows")]
+  let library = format!("mech_{}.dll", underscore_name);
+  library
+}
+
+// Where one version of a machine's library is cached
+pub fn machine_path(machine_dir: &Path, name: &str, version: &str, library: &str) -> PathBuf {
+  machine_dir.join(name).join(version).join(library)
+}
+
+// Registries and machines are downloaded when their location is a URL, and
+// copied when it's a local path or a file:// URL.
+pub fn is_remote(location: &str) -> bool {
+  location.starts_with("http")
+}
+
+pub fn local_path(location: &str) -> PathBuf {
+  PathBuf::from(location.strip_prefix("file://").unwrap_or(location))
+}
+
+// ## Registry
+
+// Where programs get their registry unless they're told otherwise
+pub const DEFAULT_REGISTRY: &str = "https://gitlab.com/mech-lang/machines/mech/-/raw/v0.1-beta/src/registry.mec";
+
+// How long a downloaded registry is used before it's fetched again
+pub const DEFAULT_REGISTRY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How long ago a cached registry was fetched
+pub fn registry_age(path: &Path) -> Option<Duration> {
+  fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?.elapsed().ok()
+}
+
+// A machine as the registry lists it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegistryEntry {
+  pub name: String,
+  pub version: String,
+  pub url: String,
+  pub sha256: Option<String>, // Hex digest of the library
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub mirrors: Vec<String>, // Other URLs the same version can be downloaded from, in order
+}
+
+impl RegistryEntry {
+
+  // Where the library can be fetched from, in the order to try them
+  pub fn urls(&self) -> impl Iterator<Item=&String> {
+    std::iter::once(&self.url).chain(self.mirrors.iter())
+  }
+
+  // The name and version become directories under the machine directory,
+  // so they have to stay single path components inside it
+  pub fn check_path(&self, source: &str) -> Result<(),MechError> {
+    for (field, value) in &[("name", &self.name), ("version", &self.version)] {
+      if value.is_empty() || *value == "." || value.contains("..") || value.contains(|c| c == '/' || c == '\\' || c == ':' || c == '\0') {
+        return Err(MechError{msg: "".to_string(), id: 1280, kind: MechErrorKind::GenericError(format!("{} lists a machine with {} {:?}, which isn't a valid directory name", source, field, value))});
+      }
+    }
+    Ok(())
+  }
+
+}
+
+// Reads the machines listed in a registry's mech/registry table, with every
+// version of a machine in the order they're listed. The sha256 and
+// description columns are optional.
+pub fn read_registry(code: &str) -> Result<HashMap<String,Vec<RegistryEntry>>,MechError> {
+  let mut registry_compiler = Compiler::new();
+  let sections = registry_compiler.compile_str(code)?;
+  let mut registry_core = Core::new();
+  registry_core.load_sections(sections);
+
+  let registry_table = registry_core.get_table("mech/registry")?;
+  let registry_table_brrw = registry_table.borrow();
+  let string = |row: usize, column: &str| -> Result<Option<String>,MechError> {
+    match registry_table_brrw.get_by_index(TableIndex::Index(row+1), TableIndex::Alias(hash_str(column))) {
+      Ok(value) => Ok(value.as_string().ok().map(|string| string.to_string())),
+      Err(_) if column == "sha256" || column == "description" => Ok(None),
+      Err(err) => Err(err),
+    }
+  };
+  let mut entries: HashMap<String,Vec<RegistryEntry>> = HashMap::new();
+  for row in 0..registry_table_brrw.rows {
+    let name = string(row, "name")?.unwrap_or_default();
+    let entry = RegistryEntry{
+      name: name.clone(),
+      version: string(row, "version")?.unwrap_or_default(),
+      url: string(row, "url")?.unwrap_or_default(),
+      sha256: string(row, "sha256")?.filter(|digest| !digest.is_empty()),
+      description: string(row, "description")?.filter(|description| !description.is_empty()),
+      mirrors: vec![],
+    };
+    entry.check_path("The registry")?;
+    entries.entry(name).or_default().push(entry);
+  }
+  Ok(entries)
+}
+
+// Merges a registry into machines read from registries that take precedence
+// over it. Versions the earlier registries already list get the later
+// registry's URL as a mirror.
+pub fn merge_registry(merged: &mut HashMap<String,Vec<RegistryEntry>>, registry: HashMap<String,Vec<RegistryEntry>>) {
+  for (name, entries) in registry {
+    let versions = merged.entry(name).or_default();
+    for entry in entries {
+      match versions.iter_mut().find(|listed| listed.version == entry.version) {
+        Some(listed) => {
+          if !listed.urls().any(|url| *url == entry.url) {
+            listed.mirrors.push(entry.url);
+          }
+        }
+        None => versions.push(entry),
+      }
+    }
+  }
+}
+
+// Every version of every machine whose name or description contains query,
+// ignoring case, sorted by name and then version. An empty query lists them
+// all.
+pub fn search_registry(registry: &HashMap<String,Vec<RegistryEntry>>, query: &str) -> Vec<RegistryEntry> {
+  let query = query.to_lowercase();
+  let mut found: Vec<RegistryEntry> = registry.values().flatten()
+    .filter(|entry| entry.name.to_lowercase().contains(&query) ||
+                    entry.description.as_ref().map_or(false, |description| description.to_lowercase().contains(&query)))
+    .cloned()
+    .collect();
+  found.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| parse_version(&a.version).cmp(&parse_version(&b.version))));
+  found
+}
+
+// A machine whose resolved version changed, appeared, or went away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionChange {
+  pub name: String,
+  pub old: Option<String>,
+  pub new: Option<String>,
+}
+
+// The machines that resolve differently in new than in old, by name
+pub fn version_changes(old: &HashMap<String,RegistryEntry>, new: &HashMap<String,RegistryEntry>) -> Vec<VersionChange> {
+  let mut names: Vec<&String> = old.keys().chain(new.keys().filter(|name| !old.contains_key(*name))).collect();
+  names.sort();
+  names.into_iter().filter_map(|name| {
+    let old = old.get(name).map(|entry| entry.version.clone());
+    let new = new.get(name).map(|entry| entry.version.clone());
+    match old == new {
+      true => None,
+      false => Some(VersionChange{name: name.clone(), old, new}),
+    }
+  }).collect()
+}
+
+// ## Versions
+
+// Registry versions may start with a v
+pub fn parse_version(version: &str) -> Option<Version> {
+  Version::parse(version.trim().trim_start_matches('v')).ok()
+}
+
+// The highest version that meets the requirement. Without a requirement,
+// versions that aren't semantic versions can still be picked, and the last
+// one listed wins.
+pub fn select_version<'a>(entries: &'a [RegistryEntry], requirement: Option<&VersionReq>) -> Option<&'a RegistryEntry> {
+  let highest = entries.iter()
+    .filter_map(|entry| parse_version(&entry.version).map(|version| (version, entry)))
+    .filter(|(version, _)| requirement.map_or(true, |requirement| requirement.matches(version)))
+    .max_by(|(a, _), (b, _)| a.cmp(b))
+    .map(|(_, entry)| entry);
+  match (highest, requirement) {
+    (None, None) => entries.last(),
+    (highest, _) => highest,
+  }
+}
+
+// ## Digests
+
+// The SHA-256 digest of a file in lowercase hex
+pub fn file_digest(path: &Path) -> Result<String,MechError> {
+  let mut file = File::open(path)?;
+  let mut hasher = Sha256::new();
+  io::copy(&mut file, &mut hasher)?;
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Checks a library against the digest its registry entry lists, if any
+pub fn verify_machine(path: &Path, entry: &RegistryEntry) -> Result<(),MechError> {
+  let expected = match &entry.sha256 {
+    Some(expected) => expected.to_lowercase(),
+    None => return Ok(()),
+  };
+  let actual = file_digest(path)?;
+  if actual != expected {
+    return Err(MechError{msg: format!("{} v{} doesn't match its registry digest", entry.name, entry.version), id: 1275, kind: MechErrorKind::GenericError(format!("{:?} has SHA-256 digest {}, but the registry lists {}", path, actual, expected))});
+  }
+  Ok(())
+}
+
+// ## Lockfile
+
+// Where a program's lockfile goes: mech.lock next to the program file
+pub fn program_lock_path(program_path: &Path) -> PathBuf {
+  program_path.with_file_name("mech.lock")
+}
+
+// Where a program locks the machine versions it resolves to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockLocation {
+  MachineDir,    // mech.lock in the machine directory, so it ships with vendored machines
+  File(PathBuf), // A lockfile of the program's own, like program_lock_path
+  Off,           // Resolve against the registry every time
+}
+
+impl LockLocation {
+
+  pub fn path(&self, machine_dir: &Path) -> Option<PathBuf> {
+    match self {
+      LockLocation::MachineDir => Some(machine_dir.join("mech.lock")),
+      LockLocation::File(path) => Some(path.clone()),
+      LockLocation::Off => None,
+    }
+  }
+
+}
+
+// The machine versions a program resolved to, with their digests, so later
+// builds load the same libraries whatever the registry lists by then. Digests
+// the registry doesn't provide are taken from the library once it's cached.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+  pub machines: Vec<RegistryEntry>, // Sorted by name
+}
+
+impl Lockfile {
+
+  pub fn new() -> Lockfile {
+    Lockfile::default()
+  }
+
+  // Reads a lockfile, or None if there isn't one at path
+  pub fn read(path: &Path) -> Result<Option<Lockfile>,MechError> {
+    let contents = match fs::read_to_string(path) {
+      Ok(contents) => contents,
+      Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(err.into()),
+    };
+    let lockfile: Lockfile = match serde_json::from_str(&contents) {
+      Ok(lockfile) => lockfile,
+      Err(err) => return Err(MechError{msg: "".to_string(), id: 1279, kind: MechErrorKind::GenericError(format!("Can't read lockfile {:?}: {}", path, err))}),
+    };
+    for entry in &lockfile.machines {
+      entry.check_path(&format!("Lockfile {:?}", path))?;
+    }
+    Ok(Some(lockfile))
+  }
+
+  // Writes the lockfile next to path and moves it into place, so a lockfile
+  // is never left half written
+  pub fn write(&self, path: &Path) -> Result<(),MechError> {
+    let mut contents = serde_json::to_string_pretty(self).unwrap();
+    contents.push('\n');
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+  }
+
+  pub fn get(&self, name: &str) -> Option<&RegistryEntry> {
+    self.machines.iter().find(|entry| entry.name == name)
+  }
+
+  // Locks an entry, replacing the machine's old one. Returns whether the
+  // lockfile changed.
+  pub fn insert(&mut self, entry: RegistryEntry) -> bool {
+    match self.machines.binary_search_by(|locked| locked.name.cmp(&entry.name)) {
+      Ok(ix) if self.machines[ix] == entry => false,
+      Ok(ix) => {
+        self.machines[ix] = entry;
+        true
+      }
+      Err(ix) => {
+        self.machines.insert(ix, entry);
+        true
+      }
+    }
+  }
+
+}
+
+// ## Cached Machines
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CachedMachine {
+  pub name: String,
+  pub version: String,
+  pub path: PathBuf, // The version's directory
+}
+
+// Every version of every machine in the cache, sorted by name and version.
+// Files at the top of the cache, like the registry, aren't machines.
+pub fn cached_machines(machine_dir: &Path) -> Result<Vec<CachedMachine>,MechError> {
+  let mut cached = vec![];
+  let names = match fs::read_dir(machine_dir) {
+    Ok(names) => names,
+    Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(cached),
+    Err(err) => return Err(err.into()),
+  };
+  for name in names {
+    let name = name?;
+    if !name.file_type()?.is_dir() {
+      continue;
+    }
+    for version in fs::read_dir(name.path())? {
+      let version = version?;
+      if !version.file_type()?.is_dir() {
+        continue;
+      }
+      cached.push(CachedMachine{
+        name: name.file_name().to_string_lossy().to_string(),
+        version: version.file_name().to_string_lossy().to_string(),
+        path: version.path(),
+      });
+    }
+  }
+  cached.sort();
+  Ok(cached)
+}
+
+// Removes every cached version that keep() turns down, and returns them.
+// Machines left with no versions are removed too.
+pub fn prune_machines(machine_dir: &Path, keep: impl Fn(&CachedMachine) -> bool) -> Result<Vec<CachedMachine>,MechError> {
+  let mut pruned = vec![];
+  for machine in cached_machines(machine_dir)? {
+    if keep(&machine) {
+      continue;
+    }
+    fs::remove_dir_all(&machine.path)?;
+    let name_dir = machine_dir.join(&machine.name);
+    if fs::read_dir(&name_dir)?.next().is_none() {
+      fs::remove_dir(&name_dir)?;
+    }
+    pruned.push(machine);
+  }
+  Ok(pruned)
+}
+
+// ## Loading Machines
+
+// Whether a machine can be loaded without going to the network, because it's
+// cached or the registry lists it at a local path.
+pub fn machine_available(machine_dir: &Path, entry: &RegistryEntry) -> bool {
+  let library = machine_library(&entry.name);
+  machine_path(machine_dir, &entry.name, &entry.version, &library).is_file() ||
+  entry.urls().any(|url| !is_remote(url) && local_path(&format!("{}{}", url, library)).is_file())
+}
+
+// Loads a version of a machine from the cache, downloading it first if it
+// isn't there.
+pub fn load_machine(machine_dir: &Path, library: &str, entry: &RegistryEntry, offline: bool, outgoing: Option<Sender<ClientMessage>>) -> Result<Library,MechError> {
+  let path = machine_path(machine_dir, &entry.name, &entry.version, library);
+  if !path.is_file() {
+    return download_machine(machine_dir, library, entry, offline, outgoing);
+  }
+  match &outgoing {
+    Some(sender) => {sender.send(ClientMessage::String(format!("{} {} v{}", "[Loading]".truecolor(153,221,85), entry.name, entry.version)));}
+    None => (),
+  }
+  verify_machine(&path, entry)?;
+  match unsafe{Library::new(&path)} {
+    Ok(machine) => Ok(machine),
+    Err(err) => Err(MechError{msg: "".to_string(), id: 1274, kind: MechErrorKind::GenericError(format!("Can't load library {:?}: {}", path, err))}),
+  }
+}
+
+// ## Vendoring
+
+// Fetches everything a program needs into dest ahead of time: the registry
+// and every machine that provides a function or table the program is missing.
+// Nothing is loaded. dest is laid out like any machine directory, so it can be
+// shipped and used as an offline program's machine directory.
+pub fn vendor_machines(program_path: &Path, registries: &[String], dest: &Path, outgoing: Option<Sender<ClientMessage>>) -> Result<Vec<RegistryEntry>,MechError> {
+  let code = fs::read_to_string(program_path)?;
+  let (program_outgoing, program_incoming) = crossbeam_channel::unbounded();
+  let mut program = Program::new("vendor", 100, 1000, program_outgoing, program_incoming, DEFAULT_REGISTRY.to_string());
+  program.registries = registries.to_vec();
+  program.machine_dir = dest.to_path_buf();
+  program.lock = LockLocation::File(program_lock_path(program_path));
+  program.compile_program(code)?;
+  program.vendor(outgoing)
+}
@@ -0,0 +1,272 @@
+// # Migration
+
+// ## Prelude
+
+use mech_core::*;
+use hashbrown::HashMap;
+use super::persister::change_table_id;
+
+// ## Migration
+
+// How to bring persisted changes in line with the current program before
+// they are replayed: tables and column aliases can be renamed, and the
+// values in a column converted to another kind. Columns are named as they
+// are after renaming.
+#[derive(Debug, Clone, Default)]
+pub struct Migration {
+  pub tables: HashMap<u64,u64>,            // Old table id -> new table id
+  pub columns: HashMap<(u64,u64),u64>,     // (New table id, old alias) -> new alias
+  pub kinds: HashMap<(u64,u64),ValueKind>, // (New table id, alias) -> kind the column's values become
+}
+
+impl Migration {
+
+  pub fn new() -> Migration {
+    Migration::default()
+  }
+
+  pub fn rename_table(mut self, old: &str, new: &str) -> Migration {
+    self.tables.insert(hash_str(old), hash_str(new));
+    self
+  }
+
+  pub fn rename_column(mut self, table: &str, old: &str, new: &str) -> Migration {
+    self.columns.insert((hash_str(table), hash_str(old)), hash_str(new));
+    self
+  }
+
+  pub fn convert_column(mut self, table: &str, column: &str, kind: ValueKind) -> Migration {
+    self.kinds.insert((hash_str(table), hash_str(column)), kind);
+    self
+  }
+
+  pub fn replay(&self) -> Replay {
+    Replay{migration: self, tables: HashMap::new()}
+  }
+
+}
+
+// What replay has learned about a table from the log so far
+#[derive(Debug, Default)]
+struct TableShape {
+  aliases: HashMap<u64,usize>, // Alias -> column index
+  kinds: HashMap<usize,ValueKind>,
+}
+
+impl TableShape {
+
+  fn alias(&self, column_ix: usize) -> Option<u64> {
+    self.aliases.iter().find(|(_,ix)| **ix == column_ix).map(|(alias,_)| *alias)
+  }
+
+}
+
+fn table_error(id: u64, table_id: u64, message: String) -> MechError {
+  MechError{msg: "".to_string(), id, kind: MechErrorKind::GenericError(format!("Can't replay #{}: {}", humanize(&table_id), message))}
+}
+
+// Migrates a log one transaction at a time. It keeps track of the shape of
+// each table as the log describes it, so every change can be checked before
+// it is applied. Changes that would land in a column that no longer exists,
+// in a column that has moved, or in a column of another kind are errors.
+pub struct Replay<'a> {
+  migration: &'a Migration,
+  tables: HashMap<u64,TableShape>,
+}
+
+impl<'a> Replay<'a> {
+
+  pub fn migrate(&mut self, txn: &Transaction, core: &Core) -> Result<Transaction,MechError> {
+    let renamed: Transaction = txn.iter().map(|change| self.rename(change)).collect();
+    // Learn the shape first, since kinds can come before the aliases that
+    // name their columns
+    for change in &renamed {
+      match change {
+        Change::NewTable{table_id,..} => {
+          self.tables.insert(*table_id, TableShape::default());
+        }
+        Change::ColumnAlias{table_id, column_ix, column_alias} => {
+          self.check_column(core, *table_id, *column_ix, *column_alias)?;
+          self.tables.entry(*table_id).or_default().aliases.insert(*column_alias, *column_ix);
+        }
+        _ => (),
+      }
+    }
+    let mut migrated = vec![];
+    for change in renamed {
+      migrated.push(match change {
+        Change::ColumnKind{table_id, column_ix, column_kind} => {
+          let column_kind = self.target_kind(table_id, column_ix).unwrap_or(column_kind);
+          self.tables.entry(table_id).or_default().kinds.insert(column_ix, column_kind.clone());
+          Change::ColumnKind{table_id, column_ix, column_kind}
+        }
+        Change::Set((table_id, values)) => {
+          let mut converted = vec![];
+          for (row, column, value) in values {
+            let value = self.convert(table_id, &column, value)?;
+            converted.push((row, column, value));
+          }
+          Change::Set((table_id, converted))
+        }
+        change => change,
+      });
+    }
+    Ok(migrated)
+  }
+
+  fn rename(&self, change: &Change) -> Change {
+    let table_id = change_table_id(change);
+    let new_id = *self.migration.tables.get(&table_id).unwrap_or(&table_id);
+    let rename_alias = |alias: u64| *self.migration.columns.get(&(new_id, alias)).unwrap_or(&alias);
+    match change {
+      Change::NewTable{rows, columns, ..} => Change::NewTable{table_id: new_id, rows: *rows, columns: *columns},
+      Change::ColumnKind{column_ix, column_kind, ..} => Change::ColumnKind{table_id: new_id, column_ix: *column_ix, column_kind: column_kind.clone()},
+      Change::ColumnAlias{column_ix, column_alias, ..} => Change::ColumnAlias{table_id: new_id, column_ix: *column_ix, column_alias: rename_alias(*column_alias)},
+      Change::Set((_, values)) => Change::Set((new_id, values.iter().map(|(row, column, value)| {
+        let column = match column {
+          TableIndex::Alias(alias) => TableIndex::Alias(rename_alias(*alias)),
+          column => column.clone(),
+        };
+        (row.clone(), column, value.clone())
+      }).collect())),
+    }
+  }
+
+  // A column the log names has to be in the same place in the running
+  // program, if the program already has the table.
+  fn check_column(&self, core: &Core, table_id: u64, column_ix: usize, alias: u64) -> Result<(),MechError> {
+    if let Some(table) = core.database.borrow().get_table_by_id(&table_id) {
+      let table = table.borrow();
+      match table.col_map.get_index(&alias) {
+        Ok(ix) if ix != column_ix => {
+          return Err(table_error(1311, table_id, format!("column {} was column {} and is now column {}. Add a migration that moves it.", humanize(&alias), column_ix + 1, ix + 1)));
+        }
+        Err(_) if table.col_map.alias_to_ix.len() > 0 => {
+          return Err(table_error(1312, table_id, format!("column {} no longer exists. Add a migration that renames it.", humanize(&alias))));
+        }
+        _ => (),
+      }
+    }
+    Ok(())
+  }
+
+  fn target_kind(&self, table_id: u64, column_ix: usize) -> Option<ValueKind> {
+    let alias = self.tables.get(&table_id)?.alias(column_ix)?;
+    self.migration.kinds.get(&(table_id, alias)).cloned()
+  }
+
+  fn convert(&self, table_id: u64, column: &TableIndex, value: Value) -> Result<Value,MechError> {
+    let shape = match self.tables.get(&table_id) {
+      Some(shape) => shape,
+      None => return Ok(value),
+    };
+    let column_ix = match column {
+      TableIndex::Index(ix) if *ix > 0 => ix - 1,
+      TableIndex::Alias(alias) => match shape.aliases.get(alias) {
+        Some(ix) => *ix,
+        None if shape.aliases.is_empty() => return Ok(value),
+        None => return Err(table_error(1312, table_id, format!("column {} doesn't exist. Add a migration that renames it.", humanize(alias)))),
+      },
+      _ => return Ok(value),
+    };
+    let kind = match shape.kinds.get(&column_ix) {
+      Some(kind) => kind,
+      None => return Ok(value),
+    };
+    match (value.kind(), kind) {
+      (ref value_kind, kind) if value_kind == kind => Ok(value),
+      // Kinds that don't say what values the column holds
+      (_, ValueKind::Any) | (_, ValueKind::Empty) | (_, ValueKind::Quantity) | (_, ValueKind::NumberLiteral) | (_, ValueKind::Index) | (_, ValueKind::Compound(_)) => Ok(value),
+      (ValueKind::Empty, _) => Ok(value),
+      // Table::set stores U64 values in F32 columns itself, so logs hold them
+      (ValueKind::U64, ValueKind::F32) => Ok(value),
+      (value_kind, kind) => match (self.target_kind(table_id, column_ix), convert_value(&value, kind)) {
+        (Some(_), Some(converted)) => Ok(converted),
+        (None, _) => Err(table_error(1313, table_id, format!("column {} holds {:?} but the log has a {:?} value. Add a migration that converts it.", column_ix + 1, kind, value_kind))),
+        (Some(_), None) => Err(table_error(1314, table_id, format!("can't convert {:?} in column {} to {:?}", value, column_ix + 1, kind))),
+      }
+    }
+  }
+
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+  match value {
+    Value::U8(v) => Some(v.unwrap() as f64),
+    Value::U16(v) => Some(v.unwrap() as f64),
+    Value::U32(v) => Some(v.unwrap() as f64),
+    Value::U64(v) => Some(v.unwrap() as f64),
+    Value::U128(v) => Some(v.unwrap() as f64),
+    Value::I8(v) => Some(v.unwrap() as f64),
+    Value::I16(v) => Some(v.unwrap() as f64),
+    Value::I32(v) => Some(v.unwrap() as f64),
+    Value::I64(v) => Some(v.unwrap() as f64),
+    Value::I128(v) => Some(v.unwrap() as f64),
+    Value::f32(v) => Some(*v as f64),
+    Value::F32(v) => Some(v.unwrap() as f64),
+    Value::F64(v) => Some(v.unwrap()),
+    _ => None,
+  }
+}
+
+// The value of an integer, or of a float that holds a whole number
+fn as_integer(value: &Value) -> Option<i128> {
+  match value {
+    Value::U8(v) => Some(v.unwrap() as i128),
+    Value::U16(v) => Some(v.unwrap() as i128),
+    Value::U32(v) => Some(v.unwrap() as i128),
+    Value::U64(v) => Some(v.unwrap() as i128),
+    Value::U128(v) => i128::try_from(v.unwrap()).ok(),
+    Value::I8(v) => Some(v.unwrap() as i128),
+    Value::I16(v) => Some(v.unwrap() as i128),
+    Value::I32(v) => Some(v.unwrap() as i128),
+    Value::I64(v) => Some(v.unwrap() as i128),
+    Value::I128(v) => Some(v.unwrap()),
+    value => as_f64(value).filter(|x| x.fract() == 0.0 && x.abs() < I128_RANGE).map(|x| x as i128),
+  }
+}
+
+// Floats at or past 2^127 saturate when cast to i128
+const I128_RANGE: f64 = 170141183460469231731687303715884105728.0;
+
+// Whether a float holds exactly the integer x
+fn exactly(y: f64, x: i128) -> bool {
+  y.abs() < I128_RANGE && y as i128 == x
+}
+
+// Converts between number kinds. Integers convert to integers and floats
+// exactly or not at all, and floats convert to integers only when they hold
+// a whole number that fits. Floats can round into a narrower float but not
+// overflow it, so nothing is lost without an error beyond float precision.
+fn convert_value(value: &Value, kind: &ValueKind) -> Option<Value> {
+  let x = as_f64(value)?;
+  let float = match value {
+    Value::f32(_) | Value::F32(_) | Value::F64(_) => true,
+    _ => false,
+  };
+  let narrowed = Some(x as f32).filter(|y| y.is_finite() || !x.is_finite());
+  match kind {
+    ValueKind::f32 if float => narrowed.map(Value::f32),
+    ValueKind::F32 if float => narrowed.map(|y| Value::F32(F32::new(y))),
+    ValueKind::F64 if float => Some(Value::F64(F64::new(x))),
+    kind => {
+      let n = as_integer(value)?;
+      match kind {
+        ValueKind::U8 => u8::try_from(n).ok().map(|n| Value::U8(U8::new(n))),
+        ValueKind::U16 => u16::try_from(n).ok().map(|n| Value::U16(U16::new(n))),
+        ValueKind::U32 => u32::try_from(n).ok().map(|n| Value::U32(U32::new(n))),
+        ValueKind::U64 => u64::try_from(n).ok().map(|n| Value::U64(U64::new(n))),
+        ValueKind::U128 => u128::try_from(n).ok().map(|n| Value::U128(U128::new(n))),
+        ValueKind::I8 => i8::try_from(n).ok().map(|n| Value::I8(I8::new(n))),
+        ValueKind::I16 => i16::try_from(n).ok().map(|n| Value::I16(I16::new(n))),
+        ValueKind::I32 => i32::try_from(n).ok().map(|n| Value::I32(I32::new(n))),
+        ValueKind::I64 => i64::try_from(n).ok().map(|n| Value::I64(I64::new(n))),
+        ValueKind::I128 => Some(Value::I128(I128::new(n))),
+        ValueKind::f32 => Some(n as f32).filter(|y| exactly(*y as f64, n)).map(Value::f32),
+        ValueKind::F32 => Some(n as f32).filter(|y| exactly(*y as f64, n)).map(|y| Value::F32(F32::new(y))),
+        ValueKind::F64 => Some(n as f64).filter(|y| exactly(*y, n)).map(|y| Value::F64(F64::new(y))),
+        _ => None,
+      }
+    }
+  }
+}
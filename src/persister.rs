@@ -1,89 +1,1284 @@
-use mech_core::{Core, humanize, Register, Transaction, Change};
-use std::fs::{OpenOptions, File, canonicalize, create_dir};
-use std::io::{Write, BufReader, BufWriter};
+use mech_core::{Core, humanize, hash_str, Register, Transaction, Change, MechError, MechErrorKind, TableIndex, TableId};
+use mech_utilities::{MiniTable, MiniCore};
+use std::fs::{OpenOptions, File};
+use std::io::{Write, Read, BufReader, Cursor};
+use std::collections::{HashMap, VecDeque};
 use crossbeam_channel::Sender;
 use crossbeam_channel::Receiver;
 use std::thread::{self, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::mem;
+use miniz_oxide::inflate::decompress_to_vec;
+use miniz_oxide::deflate::compress_to_vec;
+
+// ## Log Format
+
+// A database file starts with a header: the magic bytes, the format
+// version as a little endian u16, and a byte naming the codec that
+// compresses every payload in the file (version 3 and older headers have no
+// codec byte and are uncompressed). Every record after the header is
+// framed as
+//
+//   [payload length: u32][crc32 of payload: u32][payload]
+//
+// with all integers little endian, and the checksum taken over the payload
+// as stored. Each payload is one bincode serialized, then compressed,
+// Batch: a transaction and the time it was written, so changes that were
+// applied together load together. Version 2 logs held a bare Transaction
+// per record and version 1 logs a single Change; both still load, with no
+// timestamps.
+//
+// A log is a sequence of numbered segments. Each segment has its own
+// header. When rotation is configured the persister starts a new segment
+// once the current one is big enough or old enough, and lists the segments
+// in the manifest. A log without a manifest is just segment 0.
+//
+// Compaction writes the state of every persisted table to a snapshot, then
+// starts a new segment and records in the manifest that the snapshot covers
+// every segment before it. The snapshot has the same header followed by a
+// single record holding the bincode serialized tables and the time it was
+// taken (version 2 snapshots hold just the tables). Loading applies the
+// snapshot first and then the segments after it. If we crash after the
+// snapshot is replaced but before the manifest is updated, the old segments
+// are replayed over a snapshot that already includes them. Changes set
+// absolute values, so that converges on the same state. Segments the
+// snapshot covers are redundant and can be deleted.
+//
+// Segments, the manifest and the snapshot are each a named stream of bytes
+// kept by a PersistBackend. With a FileBackend, segment 0 is the file at
+// <path> itself, segment n is <path>.00000n, and the manifest and snapshot
+// are <path>.manifest and <path>.snapshot.
+
+pub const MAGIC: &[u8; 6] = b"MECHDB";
+pub const FORMAT_VERSION: u16 = 4;
+const HEADER_LEN: u64 = 9;
+const RECORD_HEADER_LEN: u64 = 8;
+
+// ## Storage
+
+pub const LOG_STREAM: &str = "log";
+pub const MANIFEST_STREAM: &str = "manifest";
+pub const SNAPSHOT_STREAM: &str = "snapshot";
+
+pub fn segment_stream(segment: u64) -> String {
+  match segment {
+    0 => LOG_STREAM.to_string(),
+    _ => format!("{:06}", segment),
+  }
+}
+
+// Somewhere to keep the streams that make up a log. Appended bytes only
+// have to survive a crash once the stream is flushed. Replace swaps in new
+// contents all at once, so a crash leaves either the old stream or the new
+// one. A stream that was never written doesn't exist.
+pub trait PersistBackend: Send {
+  fn append(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError>;
+  fn flush(&mut self, stream: &str) -> Result<(),MechError>;
+  fn read_all(&self, stream: &str) -> Result<Option<Vec<u8>>,MechError>;
+  // Reads a stream from the start, a piece at a time. Backends that can
+  // should avoid holding the whole stream in memory.
+  fn open_read(&self, stream: &str) -> Result<Option<Box<dyn Read>>,MechError> {
+    Ok(self.read_all(stream)?.map(|bytes| Box::new(Cursor::new(bytes)) as Box<dyn Read>))
+  }
+  fn truncate(&mut self, stream: &str, len: u64) -> Result<(),MechError>;
+  fn replace(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError>;
+  fn remove(&mut self, stream: &str) -> Result<(),MechError>;
+  // Bytes in the stream, 0 if it doesn't exist
+  fn size(&self, stream: &str) -> u64;
+  // How to refer to a stream in messages
+  fn describe(&self, stream: &str) -> String {
+    stream.to_string()
+  }
+}
+
+// Where a FileBackend rooted at `path` keeps a stream
+pub fn stream_path(path: &str, stream: &str) -> String {
+  match stream {
+    LOG_STREAM => path.to_string(),
+    _ => format!("{}.{}", path, stream),
+  }
+}
+
+pub fn segment_path(path: &str, segment: u64) -> String {
+  stream_path(path, &segment_stream(segment))
+}
+
+pub fn manifest_path(path: &str) -> String {
+  stream_path(path, MANIFEST_STREAM)
+}
+
+pub fn snapshot_path(path: &str) -> String {
+  stream_path(path, SNAPSHOT_STREAM)
+}
+
+// Keeps each stream in its own file next to `path`
+pub struct FileBackend {
+  pub path: String,
+  files: HashMap<String,File>, // Streams open for appending
+}
+
+impl FileBackend {
+
+  pub fn new(path: &str) -> FileBackend {
+    FileBackend{path: path.to_string(), files: HashMap::new()}
+  }
+
+  fn file(&mut self, stream: &str) -> Result<&mut File,MechError> {
+    if !self.files.contains_key(stream) {
+      let file = OpenOptions::new().create(true).append(true).open(stream_path(&self.path, stream))?;
+      self.files.insert(stream.to_string(), file);
+    }
+    Ok(self.files.get_mut(stream).unwrap())
+  }
+
+}
+
+impl PersistBackend for FileBackend {
+
+  fn append(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError> {
+    self.file(stream)?.write_all(bytes)?;
+    Ok(())
+  }
+
+  fn flush(&mut self, stream: &str) -> Result<(),MechError> {
+    if let Some(file) = self.files.get_mut(stream) {
+      file.sync_data()?;
+    }
+    Ok(())
+  }
+
+  fn read_all(&self, stream: &str) -> Result<Option<Vec<u8>>,MechError> {
+    match std::fs::read(stream_path(&self.path, stream)) {
+      Ok(bytes) => Ok(Some(bytes)),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn open_read(&self, stream: &str) -> Result<Option<Box<dyn Read>>,MechError> {
+    match File::open(stream_path(&self.path, stream)) {
+      Ok(file) => Ok(Some(Box::new(BufReader::new(file)))),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn truncate(&mut self, stream: &str, len: u64) -> Result<(),MechError> {
+    self.file(stream)?.set_len(len)?;
+    Ok(())
+  }
+
+  // Writes a temporary file, syncs it, then renames it over the stream
+  fn replace(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError> {
+    self.files.remove(stream);
+    let path = stream_path(&self.path, stream);
+    let temp_path = format!("{}.tmp", path);
+    {
+      let mut file = File::create(&temp_path)?;
+      file.write_all(bytes)?;
+      file.sync_all()?;
+    }
+    std::fs::rename(&temp_path, &path)?;
+    Ok(())
+  }
+
+  fn remove(&mut self, stream: &str) -> Result<(),MechError> {
+    self.files.remove(stream);
+    match std::fs::remove_file(stream_path(&self.path, stream)) {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+      _ => Ok(()),
+    }
+  }
+
+  fn size(&self, stream: &str) -> u64 {
+    std::fs::metadata(stream_path(&self.path, stream)).map(|m| m.len()).unwrap_or(0)
+  }
+
+  fn describe(&self, stream: &str) -> String {
+    stream_path(&self.path, stream)
+  }
+
+}
+
+// Keeps streams in memory. Clones share the same streams, so a log written
+// through one clone can be loaded through another.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+  streams: Arc<Mutex<HashMap<String,Vec<u8>>>>,
+}
+
+impl MemoryBackend {
+
+  pub fn new() -> MemoryBackend {
+    MemoryBackend::default()
+  }
+
+  pub fn streams(&self) -> Vec<String> {
+    let mut streams: Vec<String> = self.streams.lock().unwrap().keys().cloned().collect();
+    streams.sort();
+    streams
+  }
+
+}
+
+impl PersistBackend for MemoryBackend {
+
+  fn append(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError> {
+    self.streams.lock().unwrap().entry(stream.to_string()).or_default().extend_from_slice(bytes);
+    Ok(())
+  }
+
+  fn flush(&mut self, _stream: &str) -> Result<(),MechError> {
+    Ok(())
+  }
+
+  fn read_all(&self, stream: &str) -> Result<Option<Vec<u8>>,MechError> {
+    Ok(self.streams.lock().unwrap().get(stream).cloned())
+  }
+
+  fn truncate(&mut self, stream: &str, len: u64) -> Result<(),MechError> {
+    if let Some(bytes) = self.streams.lock().unwrap().get_mut(stream) {
+      bytes.truncate(len as usize);
+    }
+    Ok(())
+  }
+
+  fn replace(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError> {
+    self.streams.lock().unwrap().insert(stream.to_string(), bytes.to_vec());
+    Ok(())
+  }
+
+  fn remove(&mut self, stream: &str) -> Result<(),MechError> {
+    self.streams.lock().unwrap().remove(stream);
+    Ok(())
+  }
+
+  fn size(&self, stream: &str) -> u64 {
+    self.streams.lock().unwrap().get(stream).map_or(0, |bytes| bytes.len() as u64)
+  }
+
+}
+
+// ## Reading and Writing Logs
+
+// Where a record starts: a segment and a byte offset into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogPosition {
+  pub segment: u64,
+  pub offset: u64,
+}
+
+// How a log ended when it was read back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogEnd {
+  Clean,                 // Every record was read
+  TornTail(LogPosition), // The last record here was only partially written
+  Corrupt(LogPosition),  // The record here failed its checksum or didn't decode
+}
+
+// How record payloads are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+  None,
+  Deflate,
+}
+
+impl Codec {
+
+  fn from_byte(byte: u8) -> Option<Codec> {
+    match byte {
+      0 => Some(Codec::None),
+      1 => Some(Codec::Deflate),
+      _ => None,
+    }
+  }
+
+  fn to_byte(&self) -> u8 {
+    match self {
+      Codec::None => 0,
+      Codec::Deflate => 1,
+    }
+  }
+
+  fn compress(&self, payload: Vec<u8>) -> Vec<u8> {
+    match self {
+      Codec::None => payload,
+      Codec::Deflate => compress_to_vec(&payload, 6),
+    }
+  }
+
+  fn decompress(&self, payload: Vec<u8>) -> Option<Vec<u8>> {
+    match self {
+      Codec::None => Some(payload),
+      Codec::Deflate => decompress_to_vec(&payload).ok(),
+    }
+  }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+  version: u16,
+  codec: Codec,
+}
+
+impl Header {
+
+  fn len(&self) -> u64 {
+    match self.version {
+      1..=3 => 8,
+      _ => HEADER_LEN,
+    }
+  }
+
+}
+
+fn write_header(writer: &mut dyn Write, codec: Codec) -> std::io::Result<()> {
+  writer.write_all(MAGIC)?;
+  writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+  writer.write_all(&[codec.to_byte()])
+}
+
+fn write_record(writer: &mut dyn Write, payload: &[u8]) -> std::io::Result<()> {
+  writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+  writer.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+  writer.write_all(payload)
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+  let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+  write_record(&mut bytes, payload).unwrap();
+  bytes
+}
+
+// A header followed by the given records, compressed with `codec`
+fn encode_stream(codec: Codec, payloads: Vec<Vec<u8>>) -> Vec<u8> {
+  let mut bytes = vec![];
+  write_header(&mut bytes, codec).unwrap();
+  for payload in payloads {
+    write_record(&mut bytes, &codec.compress(payload)).unwrap();
+  }
+  bytes
+}
+
+// Reads and checks the header. Returns Ok(None) if there is no complete
+// header yet.
+fn read_header(reader: &mut dyn Read, name: &str) -> Result<Option<Header>,MechError> {
+  let mut header = [0; 8];
+  let read = read_fully(reader, &mut header)?;
+  let mut expected = MAGIC.to_vec();
+  expected.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+  // Nothing written yet, or the header itself was torn
+  if read < header.len() && header[0..read] == expected[0..read] {
+    return Ok(None);
+  }
+  if read < header.len() || &header[0..6] != MAGIC {
+    return Err(MechError{msg: "".to_string(), id: 1301, kind: MechErrorKind::GenericError(format!("{} is not a Mech database", name))});
+  }
+  let version = u16::from_le_bytes([header[6], header[7]]);
+  if version == 0 || version > FORMAT_VERSION {
+    return Err(MechError{msg: "".to_string(), id: 1302, kind: MechErrorKind::GenericError(format!("{} has format version {}, expected {} or older", name, version, FORMAT_VERSION))});
+  }
+  if version < 4 {
+    return Ok(Some(Header{version, codec: Codec::None}));
+  }
+  let mut codec = [0; 1];
+  if read_fully(reader, &mut codec)? < 1 {
+    return Ok(None);
+  }
+  match Codec::from_byte(codec[0]) {
+    Some(codec) => Ok(Some(Header{version, codec})),
+    None => Err(MechError{msg: "".to_string(), id: 1315, kind: MechErrorKind::GenericError(format!("{} is compressed with unknown codec {}", name, codec[0]))}),
+  }
+}
+
+// Like read_exact, but returns how many bytes were read before hitting the end of the file.
+fn read_fully(reader: &mut dyn Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+  let mut total = 0;
+  while total < buffer.len() {
+    match reader.read(&mut buffer[total..]) {
+      Ok(0) => break,
+      Ok(n) => total += n,
+      Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(total)
+}
+
+// One record in the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Batch {
+  pub timestamp: Option<u64>, // Milliseconds since the Unix epoch when it was written, if known
+  pub changes: Transaction,
+}
+
+fn decode_record(header: Header, payload: &[u8]) -> Option<Batch> {
+  let payload = header.codec.decompress(payload.to_vec())?;
+  match header.version {
+    1 => bincode::deserialize::<Change>(&payload).ok().map(|change| Batch{timestamp: None, changes: vec![change]}),
+    2 => bincode::deserialize::<Transaction>(&payload).ok().map(|changes| Batch{timestamp: None, changes}),
+    _ => bincode::deserialize::<Batch>(&payload).ok(),
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+enum Frame {
+  End,
+  Torn,
+  BadChecksum,
+  Record(Vec<u8>),
+}
+
+fn read_frame(reader: &mut dyn Read) -> std::io::Result<Frame> {
+  let mut record_header = [0; RECORD_HEADER_LEN as usize];
+  match read_fully(reader, &mut record_header)? {
+    0 => return Ok(Frame::End),
+    n if n < record_header.len() => return Ok(Frame::Torn),
+    _ => (),
+  }
+  let length = u32::from_le_bytes([record_header[0], record_header[1], record_header[2], record_header[3]]) as usize;
+  let crc = u32::from_le_bytes([record_header[4], record_header[5], record_header[6], record_header[7]]);
+  // The length isn't checked yet, so the payload is only allocated as it's
+  // read. A corrupt length reads as a torn record instead of a huge buffer.
+  let mut payload = vec![];
+  if Read::take(&mut *reader, length as u64).read_to_end(&mut payload)? < length {
+    return Ok(Frame::Torn);
+  }
+  if crc32fast::hash(&payload) != crc {
+    return Ok(Frame::BadChecksum);
+  }
+  Ok(Frame::Record(payload))
+}
+
+// Reads the records that follow the header of a segment, handing each
+// decoded batch to `f` along with its position.
+fn read_records(reader: &mut dyn Read, header: Header, segment: u64, f: &mut dyn FnMut(LogPosition, Batch)) -> Result<LogEnd,MechError> {
+  let mut position = LogPosition{segment, offset: header.len()};
+  loop {
+    let payload = match read_frame(reader)? {
+      Frame::End => return Ok(LogEnd::Clean),
+      Frame::Torn => return Ok(LogEnd::TornTail(position)),
+      Frame::BadChecksum => return Ok(LogEnd::Corrupt(position)),
+      Frame::Record(payload) => payload,
+    };
+    match decode_record(header, &payload) {
+      Some(batch) => f(position, batch),
+      None => return Ok(LogEnd::Corrupt(position)),
+    }
+    position.offset += RECORD_HEADER_LEN + payload.len() as u64;
+  }
+}
+
+// Reads the given segments in order, stopping at the first one that doesn't
+// end cleanly. Missing segments read as empty.
+fn read_segments(backend: &dyn PersistBackend, segments: &[u64], f: &mut dyn FnMut(LogPosition, Batch)) -> Result<LogEnd,MechError> {
+  for segment in segments {
+    let stream = segment_stream(*segment);
+    let mut reader = match backend.open_read(&stream)? {
+      Some(reader) => reader,
+      None => continue,
+    };
+    let header = match read_header(&mut *reader, &backend.describe(&stream))? {
+      Some(header) => header,
+      None => continue,
+    };
+    match read_records(&mut *reader, header, *segment, f)? {
+      LogEnd::Clean => (),
+      end => return Ok(end),
+    }
+  }
+  Ok(LogEnd::Clean)
+}
+
+// Reads a stream holding a header and a single record, like the manifest
+// or the snapshot, returning its version and decompressed payload. Returns
+// Ok(None) if the stream doesn't exist.
+fn read_single_record(backend: &dyn PersistBackend, stream: &str, corrupt: &dyn Fn() -> MechError) -> Result<Option<(u16,Vec<u8>)>,MechError> {
+  let bytes = match backend.read_all(stream)? {
+    Some(bytes) => bytes,
+    None => return Ok(None),
+  };
+  let mut reader = &bytes[..];
+  let header = match read_header(&mut reader, &backend.describe(stream))? {
+    Some(header) => header,
+    None => return Err(corrupt()),
+  };
+  match read_frame(&mut reader)? {
+    Frame::Record(payload) => match header.codec.decompress(payload) {
+      Some(payload) => Ok(Some((header.version, payload))),
+      None => Err(corrupt()),
+    },
+    _ => Err(corrupt()),
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+  first_live: u64,    // Segments before this one are covered by the snapshot
+  segments: Vec<u64>, // Every segment still stored, in order
+}
+
+impl Manifest {
+
+  fn current(&self) -> u64 {
+    *self.segments.last().unwrap_or(&0)
+  }
+
+  fn live_segments(&self) -> Vec<u64> {
+    self.segments.iter().filter(|segment| **segment >= self.first_live).cloned().collect()
+  }
+
+}
+
+fn read_manifest(backend: &dyn PersistBackend) -> Result<Manifest,MechError> {
+  let corrupt = || MechError{msg: "".to_string(), id: 1308, kind: MechErrorKind::GenericError(format!("{} is corrupt", backend.describe(MANIFEST_STREAM)))};
+  match read_single_record(backend, MANIFEST_STREAM, &corrupt)? {
+    Some((_, payload)) => bincode::deserialize(&payload).map_err(|_| corrupt()),
+    None => Ok(Manifest{first_live: 0, segments: vec![0]}),
+  }
+}
+
+fn write_manifest(backend: &mut dyn PersistBackend, manifest: &Manifest) -> Result<(),MechError> {
+  backend.replace(MANIFEST_STREAM, &encode_stream(Codec::None, vec![bincode::serialize(manifest).unwrap()]))
+}
+
+// Deletes the segments the snapshot covers. The manifest stops listing them
+// before any stream is removed, so a crash part way leaves only strays.
+fn prune_segments(backend: &mut dyn PersistBackend, manifest: &mut Manifest) -> Result<usize,MechError> {
+  let first_live = manifest.first_live;
+  let redundant: Vec<u64> = manifest.segments.iter().filter(|segment| **segment < first_live).cloned().collect();
+  if redundant.is_empty() {
+    return Ok(0);
+  }
+  manifest.segments.retain(|segment| *segment >= first_live);
+  write_manifest(backend, manifest)?;
+  for segment in &redundant {
+    backend.remove(&segment_stream(*segment))?;
+  }
+  Ok(redundant.len())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Snapshot {
+  timestamp: Option<u64>, // Milliseconds since the Unix epoch when it was taken, if known
+  tables: Vec<MiniTable>,
+}
+
+// Reads the tables saved by the last compaction, if there was one.
+fn read_snapshot(backend: &dyn PersistBackend) -> Result<Snapshot,MechError> {
+  let corrupt = || MechError{msg: "".to_string(), id: 1305, kind: MechErrorKind::GenericError(format!("{} is corrupt", backend.describe(SNAPSHOT_STREAM)))};
+  match read_single_record(backend, SNAPSHOT_STREAM, &corrupt)? {
+    Some((version, payload)) if version < 3 => {
+      bincode::deserialize(&payload).map(|tables| Snapshot{timestamp: None, tables}).map_err(|_| corrupt())
+    }
+    Some((_, payload)) => bincode::deserialize(&payload).map_err(|_| corrupt()),
+    None => Ok(Snapshot::default()),
+  }
+}
+
+fn write_snapshot(backend: &mut dyn PersistBackend, snapshot: &Snapshot, codec: Codec) -> Result<(),MechError> {
+  backend.replace(SNAPSHOT_STREAM, &encode_stream(codec, vec![bincode::serialize(snapshot).unwrap()]))
+}
+
+// Rebuilds a table from a snapshot as the changes that would create it.
+pub fn table_to_changes(table: &MiniTable) -> Transaction {
+  let mut changes = vec![Change::NewTable{table_id: table.id, rows: table.rows, columns: table.cols}];
+  for (ix,kind) in table.col_kinds.iter().enumerate() {
+    changes.push(Change::ColumnKind{table_id: table.id, column_ix: ix, column_kind: kind.clone()});
+  }
+  let (_,_,aliases) = &table.col_map;
+  for (alias,ix) in aliases {
+    changes.push(Change::ColumnAlias{table_id: table.id, column_ix: *ix, column_alias: *alias});
+  }
+  let mut values = vec![];
+  for (col,column) in table.data.iter().enumerate() {
+    for (row,value) in column.iter().enumerate() {
+      values.push((TableIndex::Index(row+1), TableIndex::Index(col+1), value.clone()));
+    }
+  }
+  changes.push(Change::Set((table.id, values)));
+  changes
+}
+
+pub fn change_table_id(change: &Change) -> u64 {
+  match change {
+    Change::Set((table_id,_)) => *table_id,
+    Change::NewTable{table_id,..} => *table_id,
+    Change::ColumnAlias{table_id,..} => *table_id,
+    Change::ColumnKind{table_id,..} => *table_id,
+  }
+}
+
+fn stopped() -> MechError {
+  MechError{msg: "".to_string(), id: 1307, kind: MechErrorKind::GenericError("The persister has stopped".to_string())}
+}
+
+// Rewrites a segment from an older format version in the current one,
+// returning the new contents. A torn record at the end is dropped, as it
+// would be from a current segment. A corrupt one leaves the segment as it
+// is, since rewriting it would lose every record after it.
+fn upgrade_log(backend: &mut dyn PersistBackend, stream: &str, bytes: &[u8], header: Header) -> Result<Vec<u8>,MechError> {
+  let mut reader = &bytes[header.len() as usize..];
+  let mut payloads = vec![];
+  match read_records(&mut reader, header, 0, &mut |_, batch| payloads.push(bincode::serialize(&batch).unwrap()))? {
+    LogEnd::Corrupt(position) => {
+      return Err(MechError{msg: "".to_string(), id: 1316, kind: MechErrorKind::GenericError(format!("{} is corrupt at byte {}, so it can't be upgraded from format version {}", backend.describe(stream), position.offset, header.version))});
+    }
+    _ => (),
+  }
+  let upgraded = encode_stream(header.codec, payloads);
+  backend.replace(stream, &upgraded)?;
+  Ok(upgraded)
+}
+
+// Gets a segment ready for appending and returns its size and codec. A new
+// segment gets a header naming `codec`, and a torn record left at the end by
+// a crash is cut off so new records stay readable.
+fn open_log(backend: &mut dyn PersistBackend, stream: &str, codec: Codec) -> Result<(u64,Codec),MechError> {
+  let name = backend.describe(stream);
+  let mut bytes = backend.read_all(stream)?.unwrap_or_default();
+  let mut header = read_header(&mut &bytes[..], &name)?;
+  if let Some(old) = header.filter(|header| header.version < FORMAT_VERSION) {
+    bytes = upgrade_log(backend, stream, &bytes, old)?;
+    header = Some(Header{version: FORMAT_VERSION, codec: old.codec});
+  }
+  match header {
+    Some(header) => {
+      match read_records(&mut &bytes[HEADER_LEN as usize..], header, 0, &mut |_,_| ())? {
+        LogEnd::Clean => Ok((bytes.len() as u64, header.codec)),
+        LogEnd::TornTail(position) => {
+          backend.truncate(stream, position.offset)?;
+          Ok((position.offset, header.codec))
+        }
+        LogEnd::Corrupt(position) => {
+          Err(MechError{msg: "".to_string(), id: 1303, kind: MechErrorKind::GenericError(format!("{} is corrupt at byte {}", name, position.offset))})
+        }
+      }
+    }
+    None => {
+      backend.replace(stream, &encode_stream(codec, vec![]))?;
+      Ok((HEADER_LEN, codec))
+    }
+  }
+}
+
+// ## Log Reader
+
+// Streams the changes in a log without loading it all at once, one record
+// at a time from the backend's open_read. The changes in the snapshot come first, positioned at offset 0
+// of the first live segment, followed by the changes in each record of the
+// live segments. Like load, reading stops at the first segment that doesn't
+// end cleanly; end() says how the log ended once the reader is exhausted.
+pub struct LogReader<'a> {
+  backend: &'a dyn PersistBackend,
+  segments: VecDeque<u64>,
+  segment: Option<(u64,Header,Box<dyn Read>)>, // The segment being read, its header, and a reader positioned at its next record
+  offset: u64,
+  pending: VecDeque<(LogPosition,Change)>,
+  end: LogEnd,
+  done: bool,
+}
+
+impl<'a> LogReader<'a> {
+
+  pub fn new(backend: &'a dyn PersistBackend) -> Result<LogReader<'a>,MechError> {
+    let manifest = read_manifest(backend)?;
+    let position = LogPosition{segment: manifest.first_live, offset: 0};
+    let pending = read_snapshot(backend)?.tables.iter().flat_map(table_to_changes).map(|change| (position, change)).collect();
+    Ok(LogReader {
+      backend,
+      segments: manifest.live_segments().into_iter().collect(),
+      segment: None,
+      offset: 0,
+      pending,
+      end: LogEnd::Clean,
+      done: false,
+    })
+  }
+
+  pub fn end(&self) -> LogEnd {
+    self.end
+  }
+
+  // Reads the next record into pending. Returns false once there are no more.
+  fn read_record(&mut self) -> Result<bool,MechError> {
+    loop {
+      if self.segment.is_none() {
+        let segment = match self.segments.pop_front() {
+          Some(segment) => segment,
+          None => return Ok(false),
+        };
+        let stream = segment_stream(segment);
+        let mut reader = match self.backend.open_read(&stream)? {
+          Some(reader) => reader,
+          None => continue,
+        };
+        let header = match read_header(&mut *reader, &self.backend.describe(&stream))? {
+          Some(header) => header,
+          None => continue,
+        };
+        self.segment = Some((segment, header, reader));
+        self.offset = header.len();
+      }
+      let (segment, header, frame) = match self.segment {
+        Some((segment, header, ref mut reader)) => (segment, header, read_frame(&mut **reader)?),
+        None => unreachable!(),
+      };
+      let position = LogPosition{segment, offset: self.offset};
+      let payload = match frame {
+        Frame::End => {
+          self.segment = None;
+          continue;
+        }
+        Frame::Torn => {
+          self.end = LogEnd::TornTail(position);
+          return Ok(false);
+        }
+        Frame::BadChecksum => {
+          self.end = LogEnd::Corrupt(position);
+          return Ok(false);
+        }
+        Frame::Record(payload) => payload,
+      };
+      match decode_record(header, &payload) {
+        Some(batch) => {
+          self.offset += RECORD_HEADER_LEN + payload.len() as u64;
+          self.pending.extend(batch.changes.into_iter().map(|change| (position, change)));
+          return Ok(true);
+        }
+        None => {
+          self.end = LogEnd::Corrupt(position);
+          return Ok(false);
+        }
+      }
+    }
+  }
+
+}
+
+impl<'a> Iterator for LogReader<'a> {
+  type Item = Result<(LogPosition,Change),MechError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some(item) = self.pending.pop_front() {
+        return Some(Ok(item));
+      }
+      if self.done {
+        return None;
+      }
+      match self.read_record() {
+        Ok(true) => (),
+        Ok(false) => self.done = true,
+        Err(err) => {
+          self.done = true;
+          return Some(Err(err));
+        }
+      }
+    }
+  }
+}
+
+// ## Point in Time
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointInTime {
+  Position(LogPosition), // Just after the record at this position
+  Timestamp(u64),        // Milliseconds since the Unix epoch
+}
+
+// Which records and snapshot tables make up the history of a table up to a
+// point in time. If every segment is still stored the log is replayed from
+// the start. Otherwise replay starts from the snapshot, and points before
+// it can't be rebuilt. Records without a timestamp come before any time.
+fn table_history(backend: &dyn PersistBackend, table_id: u64, at: PointInTime) -> Result<Transaction,MechError> {
+  let manifest = read_manifest(backend)?;
+  let mut changes = vec![];
+  let segments = match manifest.segments.first() {
+    Some(0) => manifest.segments.clone(),
+    _ => {
+      let snapshot = read_snapshot(backend)?;
+      let compacted = match at {
+        PointInTime::Position(position) => position.segment < manifest.first_live,
+        PointInTime::Timestamp(timestamp) => snapshot.timestamp.map_or(false, |taken| timestamp < taken),
+      };
+      if compacted {
+        return Err(MechError{msg: "".to_string(), id: 1309, kind: MechErrorKind::GenericError(format!("{:?} is before the oldest snapshot in {}", at, backend.describe(SNAPSHOT_STREAM)))});
+      }
+      changes.extend(snapshot.tables.iter().filter(|table| table.id == table_id).flat_map(table_to_changes));
+      manifest.live_segments()
+    }
+  };
+  let mut past = false;
+  read_segments(backend, &segments, &mut |position, batch| {
+    past = past || match at {
+      PointInTime::Position(at) => position > at,
+      PointInTime::Timestamp(at) => batch.timestamp.map_or(false, |timestamp| timestamp > at),
+    };
+    if !past {
+      changes.extend(batch.changes.into_iter().filter(|change| change_table_id(change) == table_id));
+    }
+  })?;
+  Ok(changes)
+}
+
+// ## Exporting
+
+// Exporters write one line per change, or per value for CSV. Table ids and
+// aliases are written as names when `names` has them, and humanized
+// otherwise. Both return how the log ended.
+
+fn name(names: &HashMap<u64,String>, id: u64) -> String {
+  names.get(&id).cloned().unwrap_or_else(|| humanize(&id))
+}
+
+fn format_index(names: &HashMap<u64,String>, index: &TableIndex) -> String {
+  match index {
+    TableIndex::Index(ix) => ix.to_string(),
+    TableIndex::Alias(alias) => name(names, *alias),
+    TableIndex::All => ":".to_string(),
+    index => format!("{:?}", index),
+  }
+}
+
+pub fn export_json_lines(reader: &mut LogReader, names: &HashMap<u64,String>, out: &mut dyn Write) -> Result<LogEnd,MechError> {
+  for item in &mut *reader {
+    let (position, change) = item?;
+    let table = name(names, change_table_id(&change));
+    let line = match &change {
+      Change::NewTable{rows, columns, ..} => {
+        json!({"segment": position.segment, "offset": position.offset, "table": table, "change": "new_table", "rows": rows, "columns": columns})
+      }
+      Change::ColumnKind{column_ix, column_kind, ..} => {
+        json!({"segment": position.segment, "offset": position.offset, "table": table, "change": "column_kind", "column": column_ix, "kind": format!("{:?}", column_kind)})
+      }
+      Change::ColumnAlias{column_ix, column_alias, ..} => {
+        json!({"segment": position.segment, "offset": position.offset, "table": table, "change": "column_alias", "column": column_ix, "alias": name(names, *column_alias)})
+      }
+      Change::Set((_, values)) => {
+        let values: Vec<serde_json::Value> = values.iter().map(|(row, column, value)| {
+          json!({"row": format_index(names, row), "column": format_index(names, column), "value": format!("{:?}", value)})
+        }).collect();
+        json!({"segment": position.segment, "offset": position.offset, "table": table, "change": "set", "values": values})
+      }
+    };
+    writeln!(out, "{}", line)?;
+  }
+  Ok(reader.end())
+}
+
+fn csv_field(field: &str) -> String {
+  if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+// Columns are segment, offset, table, change, row, column and value. Each
+// value in a Set gets its own row.
+pub fn export_csv(reader: &mut LogReader, names: &HashMap<u64,String>, out: &mut dyn Write) -> Result<LogEnd,MechError> {
+  writeln!(out, "segment,offset,table,change,row,column,value")?;
+  for item in &mut *reader {
+    let (position, change) = item?;
+    let table = name(names, change_table_id(&change));
+    let rows: Vec<[String;4]> = match &change {
+      Change::NewTable{rows, columns, ..} => vec![["new_table".to_string(), rows.to_string(), columns.to_string(), "".to_string()]],
+      Change::ColumnKind{column_ix, column_kind, ..} => vec![["column_kind".to_string(), "".to_string(), column_ix.to_string(), format!("{:?}", column_kind)]],
+      Change::ColumnAlias{column_ix, column_alias, ..} => vec![["column_alias".to_string(), "".to_string(), column_ix.to_string(), name(names, *column_alias)]],
+      Change::Set((_, values)) => values.iter().map(|(row, column, value)| {
+        ["set".to_string(), format_index(names, row), format_index(names, column), format!("{:?}", value)]
+      }).collect(),
+    };
+    for [kind, row, column, value] in rows {
+      writeln!(out, "{},{},{},{},{},{},{}", position.segment, position.offset, csv_field(&table), kind, csv_field(&row), csv_field(&column), csv_field(&value))?;
+    }
+  }
+  Ok(reader.end())
+}
+
+// ## Persistence Policy
+
+// Which tables make it into the log. A pattern is a table name, like
+// "time/timer", or a name prefix ending in "/*", like "time/*". A leading #
+// is ignored. A table is persisted if it matches no exclude pattern and,
+// when there are include patterns, matches at least one of them.
+//
+// Changes only carry table ids. Plain names are matched by their hash, but
+// prefixes need the table's name, so tables have to be named before
+// prefix patterns can match them.
+#[derive(Debug, Clone, Default)]
+pub struct PersistencePolicy {
+  pub include: Vec<String>,
+  pub exclude: Vec<String>,
+  pub names: HashMap<u64,String>,
+}
+
+impl PersistencePolicy {
+
+  pub fn new() -> PersistencePolicy {
+    PersistencePolicy::default()
+  }
+
+  pub fn include(mut self, pattern: &str) -> PersistencePolicy {
+    self.include.push(pattern.to_string());
+    self
+  }
+
+  pub fn exclude(mut self, pattern: &str) -> PersistencePolicy {
+    self.exclude.push(pattern.to_string());
+    self
+  }
+
+  pub fn name_table(&mut self, name: &str) {
+    let name = name.trim_start_matches('#');
+    self.names.insert(hash_str(name), name.to_string());
+  }
+
+  fn matches(&self, pattern: &str, table_id: u64) -> bool {
+    let pattern = pattern.trim_start_matches('#');
+    match pattern.strip_suffix('*') {
+      Some(prefix) => self.names.get(&table_id).map_or(false, |name| name.starts_with(prefix)),
+      None => hash_str(pattern) == table_id,
+    }
+  }
+
+  pub fn persists(&self, table_id: u64) -> bool {
+    !self.exclude.iter().any(|pattern| self.matches(pattern, table_id)) &&
+    (self.include.is_empty() || self.include.iter().any(|pattern| self.matches(pattern, table_id)))
+  }
+
+  // The changes in a transaction that should be persisted
+  pub fn filter(&self, txn: &Transaction) -> Transaction {
+    txn.iter().filter(|change| self.persists(change_table_id(change))).cloned().collect()
+  }
+
+}
 
 // ## Persister
 
+// When the persister asks the OS to put written records on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+  Never,         // Leave it to the OS. Fast, but a power loss can drop recent writes.
+  PerBatch,      // Sync after every transaction
+  Interval(u64), // Sync written transactions at most this many milliseconds after they arrive
+}
+
+#[derive(Debug, Clone)]
+pub struct PersisterOptions {
+  pub fsync_policy: FsyncPolicy,
+  pub max_segment_size: Option<u64>,     // Start a new segment once the current one holds this many bytes
+  pub max_segment_age: Option<Duration>, // or once it has been written to for this long
+  pub keep_redundant_segments: bool,     // Keep segments covered by a snapshot until the log is pruned
+  pub policy: PersistencePolicy,
+  pub codec: Codec,                      // How new segments and snapshots compress their records
+}
+
+impl Default for PersisterOptions {
+  fn default() -> PersisterOptions {
+    PersisterOptions {
+      fsync_policy: FsyncPolicy::Never,
+      max_segment_size: None,
+      max_segment_age: None,
+      keep_redundant_segments: false,
+      policy: PersistencePolicy::default(),
+      codec: Codec::None,
+    }
+  }
+}
+
 pub enum PersisterMessage {
   Stop,
-  Write(Vec<Change>),
+  Write(Transaction),
+  Snapshot(Vec<MiniTable>),
+  Prune,
+  NameTables(Vec<String>),
+  Flush(Sender<Result<(),MechError>>), // Replies once everything before it is on disk, or with an error if some of it never will be
+}
+
+// The log as seen from the persister thread. The log is opened on the first
+// write, so a load right after starting the persister sees the streams
+// exactly as they were left. If a write fails the log is opened again on
+// the next one.
+struct LogWriter {
+  backend: Box<dyn PersistBackend>,
+  options: PersisterOptions,
+  manifest: Manifest,
+  open: bool,
+  codec: Codec, // The current segment's codec
+  segment_size: u64,
+  segment_opened: Instant,
+  unsynced: Option<Instant>, // When the oldest write that isn't on disk yet was made
+  failures: usize, // Messages that failed since the last flush
+  log_size: Arc<AtomicU64>,
+}
+
+impl LogWriter {
+
+  fn open(&mut self) -> Result<(),MechError> {
+    if !self.open {
+      self.manifest = read_manifest(&*self.backend)?;
+      let (segment_size, codec) = open_log(&mut *self.backend, &segment_stream(self.manifest.current()), self.options.codec)?;
+      self.segment_size = segment_size;
+      self.codec = codec;
+      self.segment_opened = Instant::now();
+      let mut log_size = 0;
+      for segment in self.manifest.live_segments() {
+        log_size += self.backend.size(&segment_stream(segment));
+      }
+      self.log_size.store(log_size, Ordering::SeqCst);
+      self.open = true;
+    }
+    Ok(())
+  }
+
+  fn write(&mut self, txn: &Transaction) -> Result<(),MechError> {
+    let txn = self.options.policy.filter(txn);
+    if txn.is_empty() {
+      return Ok(());
+    }
+    let payload = bincode::serialize(&Batch{timestamp: Some(now()), changes: txn}).map_err(|e| MechError{msg: "".to_string(), id: 1306, kind: MechErrorKind::GenericError(format!("Can't serialize transaction: {:?}", e))})?;
+    self.open()?;
+    if self.segment_full() {
+      self.rotate(false)?;
+    }
+    let payload = self.codec.compress(payload);
+    self.backend.append(&segment_stream(self.manifest.current()), &encode_record(&payload))?;
+    let written = RECORD_HEADER_LEN + payload.len() as u64;
+    self.segment_size += written;
+    self.log_size.fetch_add(written, Ordering::SeqCst);
+    if self.unsynced.is_none() {
+      self.unsynced = Some(Instant::now());
+    }
+    match self.options.fsync_policy {
+      FsyncPolicy::PerBatch => self.sync(),
+      _ => Ok(()),
+    }
+  }
+
+  fn segment_full(&self) -> bool {
+    self.segment_size > HEADER_LEN && (
+      self.options.max_segment_size.map_or(false, |size| self.segment_size >= size) ||
+      self.options.max_segment_age.map_or(false, |age| self.segment_opened.elapsed() >= age))
+  }
+
+  // Starts the next segment. When `covered` is set, the snapshot covers
+  // every segment before the new one.
+  fn rotate(&mut self, covered: bool) -> Result<(),MechError> {
+    self.sync()?;
+    let next = self.manifest.current() + 1;
+    self.backend.replace(&segment_stream(next), &encode_stream(self.options.codec, vec![]))?;
+    self.codec = self.options.codec;
+    self.manifest.segments.push(next);
+    if covered {
+      self.manifest.first_live = next;
+    }
+    write_manifest(&mut *self.backend, &self.manifest)?;
+    self.segment_size = HEADER_LEN;
+    self.segment_opened = Instant::now();
+    Ok(())
+  }
+
+  fn snapshot(&mut self, tables: &Vec<MiniTable>) -> Result<(),MechError> {
+    let tables: Vec<MiniTable> = tables.iter().filter(|table| self.options.policy.persists(table.id)).cloned().collect();
+    self.open()?;
+    self.sync()?;
+    write_snapshot(&mut *self.backend, &Snapshot{timestamp: Some(now()), tables}, self.options.codec)?;
+    self.rotate(true)?;
+    self.log_size.store(HEADER_LEN, Ordering::SeqCst);
+    if !self.options.keep_redundant_segments {
+      self.prune()?;
+    }
+    Ok(())
+  }
+
+  fn prune(&mut self) -> Result<(),MechError> {
+    self.open()?;
+    prune_segments(&mut *self.backend, &mut self.manifest)?;
+    Ok(())
+  }
+
+  fn sync(&mut self) -> Result<(),MechError> {
+    if self.open {
+      self.backend.flush(&segment_stream(self.manifest.current()))?;
+    }
+    self.unsynced = None;
+    Ok(())
+  }
+
+  // Syncs for a flush. A closed log has nothing to sync, but if that's
+  // because something failed since the last flush, its data isn't on disk.
+  fn flush(&mut self) -> Result<(),MechError> {
+    self.sync()?;
+    match std::mem::replace(&mut self.failures, 0) {
+      0 => Ok(()),
+      failures => Err(MechError{msg: "".to_string(), id: 1318, kind: MechErrorKind::GenericError(format!("{} writes to the log failed since the last flush, so not everything sent is on disk", failures))}),
+    }
+  }
+
+  // How long the thread can wait for a message before it has to sync
+  fn sync_deadline(&self) -> Option<Duration> {
+    match (self.options.fsync_policy, self.unsynced) {
+      (FsyncPolicy::Interval(ms), Some(since)) => Some(Duration::from_millis(ms).saturating_sub(since.elapsed())),
+      _ => None,
+    }
+  }
+
 }
 
 pub struct Persister {
   thread: JoinHandle<()>,
   outgoing: Sender<PersisterMessage>,
-  loaded: Vec<Change>,
+  status: Receiver<MechError>,
+  log_size: Arc<AtomicU64>,
 }
 
 impl Persister {
-  pub fn new(path_ref:&str) -> Persister {
+  pub fn new<B: PersistBackend + 'static>(backend: B) -> Persister {
+    Persister::with_options(backend, PersisterOptions::default())
+  }
+
+  pub fn with_fsync_policy<B: PersistBackend + 'static>(backend: B, policy: FsyncPolicy) -> Persister {
+    Persister::with_options(backend, PersisterOptions{fsync_policy: policy, ..PersisterOptions::default()})
+  }
+
+  pub fn with_options<B: PersistBackend + 'static>(backend: B, options: PersisterOptions) -> Persister {
     let (outgoing, incoming) = crossbeam_channel::unbounded();
-    let path = path_ref.to_string();
+    let (status_outgoing, status) = crossbeam_channel::unbounded();
+    let log_size = Arc::new(AtomicU64::new(backend.size(LOG_STREAM)));
+    let manifest = Manifest{first_live: 0, segments: vec![0]};
+    let mut log = LogWriter{backend: Box::new(backend), options, manifest, open: false, codec: Codec::None, segment_size: 0, segment_opened: Instant::now(), unsynced: None, failures: 0, log_size: log_size.clone()};
     let thread = thread::spawn(move || {
-      let file = OpenOptions::new().append(true).create(true).open(&path).unwrap();
-      let mut writer = BufWriter::new(file);
       loop {
-        match incoming.recv().unwrap() {
-          PersisterMessage::Stop => { break; }
-          PersisterMessage::Write(items) => {
-            for item in items {
-              let result = bincode::serialize(&item).unwrap();
-              if let Err(e) = writer.write_all(&result) {
-                panic!("Can't persist! {:?}", e);
-              }
+        let message = match log.sync_deadline() {
+          Some(timeout) => incoming.recv_timeout(timeout).map_err(|e| e.is_timeout()),
+          None => incoming.recv().map_err(|_| false),
+        };
+        let result = match message {
+          Ok(PersisterMessage::Write(txn)) => log.write(&txn),
+          Ok(PersisterMessage::Snapshot(tables)) => log.snapshot(&tables),
+          Ok(PersisterMessage::Prune) => log.prune(),
+          Ok(PersisterMessage::NameTables(names)) => {
+            for name in names {
+              log.options.policy.name_table(&name);
+            }
+            continue;
+          }
+          Ok(PersisterMessage::Flush(reply)) => {
+            let result = log.flush();
+            if result.is_err() {
+              log.open = false;
             }
-            writer.flush().unwrap();
+            reply.send(result);
+            continue;
           }
+          // Time to sync
+          Err(true) => log.sync(),
+          Ok(PersisterMessage::Stop) | Err(false) => {
+            if let Err(err) = log.sync() {
+              status_outgoing.send(err);
+            }
+            break;
+          }
+        };
+        if let Err(err) = result {
+          log.open = false;
+          log.failures += 1;
+          status_outgoing.send(err);
         }
       }
     });
-    Persister { outgoing, thread, loaded: vec![] }
+    Persister { outgoing, thread, status, log_size }
   }
 
-  pub fn load(&mut self, path: &str) {
-    let file = match File::open(path) {
-      Ok(f) => f,
-      Err(_) => {
-        ////println!("Unable to load db: {}", path);
-        return;
-      }
-    };
-    let mut reader = BufReader::new(file);
-    loop {
-      let result:Result<Change, _> = bincode::deserialize_from(&mut reader);
-      match result {
-        Ok(change) => {
-          self.loaded.push(change);
-        },
-        Err(info) => {
-          ////println!("ran out {:?}", info);
-          break;
-        }
-      }
+  // Reads the last snapshot as a single transaction, followed by every
+  // intact transaction in the segments after it, in the order they were
+  // written. A backend with nothing in it loads as an empty log. The
+  // returned LogEnd says whether the log ended cleanly.
+  pub fn load(backend: &dyn PersistBackend) -> Result<(Vec<Transaction>,LogEnd),MechError> {
+    let mut transactions = vec![];
+    let snapshot: Transaction = read_snapshot(backend)?.tables.iter().flat_map(table_to_changes).collect();
+    if snapshot.len() > 0 {
+      transactions.push(snapshot);
+    }
+    let manifest = read_manifest(backend)?;
+    let end = read_segments(backend, &manifest.live_segments(), &mut |_, batch| transactions.push(batch.changes))?;
+    Ok((transactions, end))
+  }
+
+  // Rebuilds a table as it was at the given point in the log, without
+  // touching any running core.
+  pub fn table_at(backend: &dyn PersistBackend, table_id: u64, at: PointInTime) -> Result<MiniTable,MechError> {
+    let changes = table_history(backend, table_id, at)?;
+    let mut core = Core::new();
+    core.process_transaction(&changes)?;
+    match MiniCore::minify_core(&core).database.into_iter().find(|table| table.id == table_id) {
+      Some(table) => Ok(table),
+      None => Err(MechError{msg: "".to_string(), id: 1310, kind: MechErrorKind::MissingTable(TableId::Global(table_id))}),
     }
   }
 
-  pub fn send(&self, changes: Vec<Change>) {
-    self.outgoing.send(PersisterMessage::Write(changes)).unwrap();
+  pub fn send(&self, txn: Transaction) -> Result<(),MechError> {
+    self.outgoing.send(PersisterMessage::Write(txn)).map_err(|_| stopped())
+  }
+
+  // Replaces the log with a snapshot of the given tables. The tables should
+  // hold the state of everything that has been persisted so far.
+  pub fn snapshot(&self, tables: Vec<MiniTable>) -> Result<(),MechError> {
+    self.log_size.store(HEADER_LEN, Ordering::SeqCst);
+    self.outgoing.send(PersisterMessage::Snapshot(tables)).map_err(|_| stopped())
+  }
+
+  // Tells the persistence policy the names of tables, so name prefixes can
+  // match them. Only needed for tables that haven't been written yet.
+  pub fn name_tables(&self, names: Vec<String>) -> Result<(),MechError> {
+    self.outgoing.send(PersisterMessage::NameTables(names)).map_err(|_| stopped())
+  }
+
+  // Deletes segments made redundant by a snapshot. Only needed when the
+  // persister keeps them around.
+  pub fn prune(&self) -> Result<(),MechError> {
+    self.outgoing.send(PersisterMessage::Prune).map_err(|_| stopped())
+  }
+
+  // Blocks until everything sent so far is on disk. Fails if anything sent
+  // since the last flush couldn't be written.
+  pub fn flush(&self) -> Result<(),MechError> {
+    let (reply, done) = crossbeam_channel::bounded(1);
+    self.outgoing.send(PersisterMessage::Flush(reply)).map_err(|_| stopped())?;
+    done.recv().map_err(|_| stopped())?
+  }
+
+  // Errors the persister thread has run into since the last call
+  pub fn errors(&self) -> Vec<MechError> {
+    self.status.try_iter().collect()
+  }
+
+  // Bytes in the log since the last snapshot
+  pub fn log_size(&self) -> u64 {
+    self.log_size.load(Ordering::SeqCst)
   }
 
   pub fn wait(self) {
-    self.thread.join().unwrap();
+    self.thread.join();
   }
 
-    pub fn get_channel(&self) -> Sender<PersisterMessage> {
+  pub fn get_channel(&self) -> Sender<PersisterMessage> {
     self.outgoing.clone()
   }
 
-  pub fn get_changes(&mut self) -> Vec<Change> {
-    mem::replace(&mut self.loaded, vec![])
+  pub fn get_status_channel(&self) -> Receiver<MechError> {
+    self.status.clone()
   }
 
   pub fn close(&self) {
-    self.outgoing.send(PersisterMessage::Stop).unwrap();
+    self.outgoing.send(PersisterMessage::Stop);
   }
-}
\ No newline at end of file
+}
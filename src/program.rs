@@ -8,14 +8,15 @@ use colored::*;
 
 use std::thread::{self, JoinHandle};
 use std::collections::hash_map::Entry;
+use std::collections::VecDeque;
 use std::mem;
-use std::fs::{OpenOptions, File, canonicalize, create_dir};
+use std::fs::{OpenOptions, File, canonicalize, create_dir, create_dir_all};
 use std::io::{Write, BufReader, BufWriter, Read};
 use std::sync::Arc;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::path::{Path, PathBuf};
-use std::time::{Instant};
+use std::time::{Instant, Duration};
 
 use mech_core::*;
 use mech_syntax::compiler::Compiler;
@@ -25,8 +26,10 @@ use crossbeam_channel::Receiver;
 use hashbrown::{HashSet, HashMap};
 use indexmap::IndexSet;
 
-use super::download_machine;
-use super::persister::Persister;
+use super::cache_machine;
+use super::machines::{DEFAULT_REGISTRY_TTL, registry_age, search_registry, version_changes, VersionChange, default_machine_dir, machine_library, machine_path, is_remote, local_path, machine_available, file_digest, verify_machine, load_machine, read_registry, merge_registry, parse_version, select_version, cached_machines, prune_machines, CachedMachine, RegistryEntry, Lockfile, LockLocation};
+use semver::VersionReq;
+use super::persister::{Persister, change_table_id};
 use super::runloop::ClientMessage;
 
 use libloading::Library;
@@ -40,6 +43,7 @@ use time;
 lazy_static! {
   static ref MECH_CODE: u64 = hash_str("mech/code");
   static ref MECH_REGISTRY: u64 = hash_str("mech/registry");
+  static ref MECH_DEPENDENCIES: u64 = hash_str("mech/dependencies");
   static ref NAME: u64 = hash_str("name");
   static ref VERSION: u64 = hash_str("version");
   static ref URL: u64 = hash_str("url");
@@ -93,7 +97,8 @@ pub struct Program {
   pub libraries: HashMap<String, Option<Library>>,
   pub machines: HashMap<u64, Box<dyn Machine>>,
   pub mech_functions: HashMap<u64, Box<dyn MechFunctionCompiler>>,
-  pub machine_repository: HashMap<String, (String, String)>,  // (name, (version, url))
+  pub machine_repository: HashMap<String, RegistryEntry>,  // (name, entry) The version of each machine the program resolved to
+  pub registry_entries: HashMap<String, Vec<RegistryEntry>>, // Every version of each machine the registry lists
   capacity: usize,
   pub incoming: Receiver<RunLoopMessage>,
   pub outgoing: Sender<RunLoopMessage>,
@@ -102,7 +107,14 @@ pub struct Program {
   loaded_machines: HashSet<u64>,
   pub listeners: HashMap<(TableId,RegisterIndex,RegisterIndex),HashSet<u64>>,
   pub trigger_to_listener: HashMap<(TableId,RegisterIndex,RegisterIndex),((TableId, RegisterIndex, RegisterIndex),HashSet<u64>)>,
-  pub registry: String,
+  pub registries: Vec<String>, // Where machines are listed, in order of precedence
+  pub registry_ttl: Option<Duration>, // How long a downloaded registry is used before it's fetched again, or forever
+  pub machine_dir: PathBuf, // Where downloaded machines and the registry are cached
+  pub offline: bool, // Only load the registry and machines from local paths
+  pub lock: LockLocation, // Where the machine versions the program resolved to are locked
+  pub history: VecDeque<(Transaction,Transaction)>, // Applied transactions and their inverses, oldest first
+  pub history_limit: usize,
+  pub offset: usize, // How many transactions we've stepped back from the present
 }
 
 impl Program {
@@ -112,6 +124,7 @@ impl Program {
       name: name.to_owned(), 
       capacity,
       machine_repository: HashMap::new(), 
+      registry_entries: HashMap::new(),
       mech,
       remote_cores: HashMap::new(),
       cores: HashMap::new(),
@@ -126,10 +139,84 @@ impl Program {
       programs: 0,
       listeners: HashMap::new(),
       trigger_to_listener: HashMap::new(),
-      registry,
+      registries: vec![registry],
+      registry_ttl: Some(DEFAULT_REGISTRY_TTL),
+      machine_dir: default_machine_dir(),
+      offline: false,
+      lock: LockLocation::MachineDir,
+      history: VecDeque::new(),
+      history_limit: 1000,
+      offset: 0,
     }
   }
 
+  // Builds a transaction that undoes txn, from the state of the core before
+  // txn is applied. Set changes are undone by setting the old values back.
+  // Anything that reshapes a table is undone by restoring the whole table's
+  // column kinds, aliases and values. The table already exists, so it's
+  // restored without a NewTable. A table that txn creates can't be removed
+  // again, so it stays.
+  pub fn invert_transaction(&self, txn: &Transaction) -> Transaction {
+    let mut restored_tables = HashSet::new();
+    let mut restores = vec![];
+    let mut sets = vec![];
+    for change in txn {
+      match change {
+        Change::Set((table_id, values)) => {
+          if let Ok(table) = self.mech.get_table_by_id(*table_id) {
+            let table_brrw = table.borrow();
+            let old_values = values.iter().filter_map(|(row,col,_)| {
+              table_brrw.get(row,col).ok().map(|value| (row.clone(),col.clone(),value))
+            }).collect();
+            sets.push(Change::Set((*table_id, old_values)));
+          }
+        }
+        _ => {
+          let table_id = change_table_id(change);
+          if restored_tables.insert(table_id) {
+            if let Ok(table) = self.mech.get_table_by_id(table_id) {
+              restores.extend(table.borrow().to_changes().into_iter().filter(|change| !matches!(change, Change::NewTable{..})));
+            }
+          }
+        }
+      }
+    }
+    restores.append(&mut sets);
+    restores
+  }
+
+  // Remember an applied transaction so we can step back over it later
+  pub fn record_transaction(&mut self, txn: Transaction, inverse: Transaction) {
+    self.history.push_back((txn, inverse));
+    while self.history.len() > self.history_limit {
+      self.history.pop_front();
+    }
+  }
+
+  // Undo the most recent transaction we haven't already stepped back over,
+  // returning the new offset.
+  pub fn step_back_one(&mut self) -> Result<usize,MechError> {
+    if self.offset < self.history.len() {
+      let ix = self.history.len() - 1 - self.offset;
+      let inverse = self.history[ix].1.clone();
+      self.mech.process_transaction(&inverse)?;
+      self.offset += 1;
+    }
+    Ok(self.offset)
+  }
+
+  // Reapply the transaction we most recently stepped back over, returning the
+  // new offset.
+  pub fn step_forward_one(&mut self) -> Result<usize,MechError> {
+    if self.offset > 0 {
+      let ix = self.history.len() - self.offset;
+      let txn = self.history[ix].0.clone();
+      self.mech.process_transaction(&txn)?;
+      self.offset -= 1;
+    }
+    Ok(self.offset)
+  }
+
   pub fn trigger_machine(&mut self, register: &(TableId,RegisterIndex,RegisterIndex)) -> Result<(),MechError> {
     let (table_id,_,_) = register;
     match self.machines.get_mut(table_id.unwrap()) {
@@ -175,120 +262,330 @@ impl Program {
     */
   }
 
-  pub fn download_dependencies(&mut self, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Vec<MechErrorKind>,MechError> {
-    // Create the machines directory. If it's already there this does nothing.    
-    create_dir("machines");
-    // If the machine repository is not populated, we need to fill it by loading the registry
-    if self.machine_repository.len() == 0 {
-      let mut registry_file = match std::fs::File::open("machines/registry.mec") {
-        Ok(mut file) => {
-          // Loading machine_repository index
+  // Loads the cached registries, downloading them first if they aren't
+  // cached, and resolves the version of each machine the program will load.
+  pub fn load_registry(&mut self, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<(),MechError> {
+    // If the registry is not loaded, we need to fill it by loading the registries
+    if self.registry_entries.len() == 0 {
+      self.registry_entries = self.read_registries(true, outgoing)?;
+    }
+    self.resolve_machines()
+  }
+
+  // Fetches every registry again, unless we're offline, and resolves the
+  // program's machines against them. Returns the machines whose version
+  // changed. Locked machines keep their version until the lock is updated.
+  pub fn refresh_registry(&mut self, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Vec<VersionChange>,MechError> {
+    let old = self.machine_repository.clone();
+    self.registry_entries = self.read_registries(self.offline, outgoing)?;
+    self.resolve_machines()?;
+    Ok(version_changes(&old, &self.machine_repository))
+  }
+
+  // The registries' machines whose name or description contains query, with
+  // every version they list. The registries are loaded if they haven't been.
+  pub fn search_machines(&mut self, query: &str, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Vec<RegistryEntry>,MechError> {
+    if self.registry_entries.len() == 0 {
+      self.registry_entries = self.read_registries(true, outgoing)?;
+    }
+    Ok(search_registry(&self.registry_entries, query))
+  }
+
+  // Whether a cached registry is new enough to use without fetching it again
+  fn registry_fresh(&self, registry_path: &Path) -> bool {
+    match (self.registry_ttl, registry_age(registry_path)) {
+      (None, _) => true,
+      (Some(ttl), Some(age)) => age < ttl,
+      (Some(_), None) => false,
+    }
+  }
+
+  // Where a registry is cached. The first registry is cached as registry.mec,
+  // and the rest by a hash of where they are.
+  pub fn registry_cache_path(&self, ix: usize) -> PathBuf {
+    match ix {
+      0 => self.machine_dir.join("registry.mec"),
+      ix => self.machine_dir.join(format!("registry-{:016x}.mec", hash_str(&self.registries[ix]))),
+    }
+  }
+
+  // Reads and merges every registry that can be loaded. It's only an error if
+  // none of them can.
+  fn read_registries(&self, use_cache: bool, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<HashMap<String,Vec<RegistryEntry>>,MechError> {
+    let mut merged = HashMap::new();
+    let mut failures = vec![];
+    for (ix, location) in self.registries.iter().enumerate() {
+      // Compile machine registry and convert the machine listing into a hash map
+      match self.fetch_registry(ix, use_cache, outgoing.clone()).and_then(|registry_file| read_registry(&registry_file)) {
+        Ok(registry) => merge_registry(&mut merged, registry),
+        Err(err) => {
           match &outgoing {
-            Some(sender) => {sender.send(ClientMessage::String(format!("{} Machine registry.", "[Loading]".truecolor(153,221,85))));}
-            None => {return Err(MechError{msg: "".to_string(), id: 1244, kind: MechErrorKind::None});},
+            Some(sender) => {sender.send(ClientMessage::String(format!("{} Can't load machine registry {}", "[Error]".bright_red(), location)));}
+            None => (),
           }
-          let mut contents = String::new();
-          match file.read_to_string(&mut contents) {
-            Err(_) => {return Err(MechError{msg: "".to_string(), id: 1445, kind: MechErrorKind::None});},
-            _ => (),
-          }
-          contents
+          failures.push((location, err));
         }
-        Err(_) => {
-          // Download machine_repository index
-          match &outgoing {
-            Some(sender) => {sender.send(ClientMessage::String(format!("{} Updating machine registry from:\n{}", "[Downloading]".truecolor(153,221,85),self.registry)));}
-            None => {return Err(MechError{msg: "".to_string(), id: 1246, kind: MechErrorKind::None});},
-          }
-          // Download registry
-          let registry_url = &self.registry;
-          let mut response_text = match reqwest::get(registry_url) {
-            Ok(mut response) => {
-              match response.text() {
-                Ok(text) => {
-                  text
-                },
-                Err(_) => {return Err(MechError{msg: "".to_string(), id: 1235, kind: MechErrorKind::None});},
-              }
+      }
+    }
+    if failures.len() < self.registries.len() || failures.len() == 0 {
+      Ok(merged)
+    } else if failures.len() == 1 {
+      Err(failures.pop().unwrap().1)
+    } else {
+      let listing: Vec<String> = failures.iter().map(|(location, err)| format!("  {}: {:?}", location, err.kind)).collect();
+      Err(MechError{msg: "".to_string(), id: 1243, kind: MechErrorKind::GenericError(format!("Can't load any machine registry:\n{}", listing.join("\n")))})
+    }
+  }
+
+  // The version requirements the program declares in mech/dependencies, by
+  // machine. A machine with several rows has to meet all of them.
+  pub fn version_requirements(&self) -> Result<HashMap<String,(String,VersionReq)>,MechError> {
+    let mut requirements: HashMap<String,Vec<String>> = HashMap::new();
+    if let Ok(table) = self.mech.get_table_by_id(*MECH_DEPENDENCIES) {
+      let table_brrw = table.borrow();
+      for row in 0..table_brrw.rows {
+        let row_index = TableIndex::Index(row+1);
+        let name = table_brrw.get_by_index(row_index.clone(), TableIndex::Alias(*NAME))?.as_string()?.to_string();
+        let version = table_brrw.get_by_index(row_index.clone(), TableIndex::Alias(*VERSION))?.as_string()?.to_string();
+        requirements.entry(name).or_default().push(version);
+      }
+    }
+    let mut parsed = HashMap::new();
+    for (name, versions) in requirements {
+      let requirement = versions.join(", ");
+      match VersionReq::parse(&requirement) {
+        Ok(version_req) => {parsed.insert(name, (requirement, version_req));}
+        Err(err) => {return Err(MechError{msg: "".to_string(), id: 1241, kind: MechErrorKind::GenericError(format!("Can't read the version requirement {:?} for {}: {}", requirement, name, err))});}
+      }
+    }
+    Ok(parsed)
+  }
+
+  // Picks the version of each machine the program will load: the locked one
+  // if it meets the program's requirements, and otherwise the highest one
+  // that does. A requirement that no listed version meets is a conflict.
+  fn resolve_machines(&mut self) -> Result<(),MechError> {
+    let requirements = self.version_requirements()?;
+    let lockfile = match self.lock_path() {
+      Some(lock_path) => Lockfile::read(&lock_path)?.unwrap_or_default(),
+      None => Lockfile::new(),
+    };
+    let meets = |entry: &RegistryEntry| match requirements.get(&entry.name) {
+      Some((_, version_req)) => parse_version(&entry.version).map_or(false, |version| version_req.matches(&version)),
+      None => true,
+    };
+    let mut resolved = HashMap::new();
+    // Locked machines the registry doesn't list any more stay locked
+    for entry in lockfile.machines.iter().filter(|entry| meets(entry)) {
+      resolved.insert(entry.name.clone(), entry.clone());
+    }
+    for (name, entries) in &self.registry_entries {
+      if resolved.contains_key(name) {
+        continue;
+      }
+      match select_version(entries, requirements.get(name).map(|(_, version_req)| version_req)) {
+        Some(entry) => {resolved.insert(name.clone(), entry.clone());}
+        None => (),
+      }
+    }
+    for (name, (requirement, _)) in &requirements {
+      if !resolved.contains_key(name) {
+        let versions: Vec<String> = self.registry_entries.get(name).map_or(vec![], |entries| entries.iter().map(|entry| entry.version.clone()).collect());
+        let available = match versions.len() {
+          0 => "The registry doesn't list it.".to_string(),
+          _ => format!("The registry lists {}.", versions.join(", ")),
+        };
+        return Err(MechError{msg: format!("No version of {} meets {}", name, requirement), id: 1242, kind: MechErrorKind::GenericError(format!("No version of {} meets {}. {}", name, requirement, available))});
+      }
+    }
+    self.machine_repository = resolved;
+    Ok(())
+  }
+
+  // A registry's source, from the cache if use_cache is set and the cached
+  // copy is fresh. Copies of local registries are always fresh, and so is
+  // every copy when we're offline.
+  fn fetch_registry(&self, ix: usize, use_cache: bool, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<String,MechError> {
+    // Create the machines directory. If it's already there this does nothing.
+    create_dir_all(&self.machine_dir)?;
+    let registry = &self.registries[ix];
+    let registry_path = self.registry_cache_path(ix);
+    let fresh = self.offline || !is_remote(registry) || self.registry_fresh(&registry_path);
+    let cached = match use_cache && fresh {
+      true => std::fs::File::open(&registry_path).ok(),
+      false => None,
+    };
+    let registry_file = match cached {
+      Some(mut file) => {
+        // Loading machine_repository index
+        match &outgoing {
+          Some(sender) => {sender.send(ClientMessage::String(format!("{} Machine registry.", "[Loading]".truecolor(153,221,85))));}
+          None => (),
+        }
+        let mut contents = String::new();
+        match file.read_to_string(&mut contents) {
+          Err(_) => {return Err(MechError{msg: "".to_string(), id: 1445, kind: MechErrorKind::None});},
+          _ => (),
+        }
+        contents
+      }
+      // A local registry is read where it is
+      None if !is_remote(registry) => {
+        match &outgoing {
+          Some(sender) => {sender.send(ClientMessage::String(format!("{} Machine registry from:\n{}", "[Loading]".truecolor(153,221,85), registry)));}
+          None => (),
+        }
+        std::fs::read_to_string(local_path(registry))?
+      }
+      // Offline, the registry has to be a local file
+      None if self.offline => {
+        return Err(MechError{msg: "".to_string(), id: 1239, kind: MechErrorKind::GenericError(format!("Offline, and there's no machine registry at {:?}. The registry {} has to be downloaded.", registry_path, registry))});
+      }
+      None => match self.download_registry(registry, &registry_path, &outgoing) {
+        Ok(registry_file) => registry_file,
+        // A stale registry is better than none
+        Err(err) => match std::fs::read_to_string(&registry_path) {
+          Ok(registry_file) => {
+            match &outgoing {
+              Some(sender) => {sender.send(ClientMessage::String(format!("{} Can't update machine registry from {}, using the cached one.", "[Warning]".truecolor(246,192,78), registry)));}
+              None => (),
             }
-            Err(_) => {return Err(MechError{msg: "".to_string(), id: 1236, kind: MechErrorKind::None});},
-          };
-          // Save registry
-          let mut dest = match File::create("machines/registry.mec") {
-            Ok(dest) => dest,
-            Err(_) => {return Err(MechError{msg: "".to_string(), id: 1237, kind: MechErrorKind::None});},
-          };
-          match dest.write_all(response_text.as_bytes()) {
-            Ok(dest) => dest,
-            Err(_) => {return Err(MechError{msg: "".to_string(), id: 1238, kind: MechErrorKind::None});},            
+            registry_file
           }
-          response_text
+          Err(_) => return Err(err),
         }
-      };
-      
-      // Compile machine registry
-      let mut registry_compiler = Compiler::new();
-      let sections = registry_compiler.compile_str(&registry_file)?;
-      let mut registry_core = Core::new();
-      registry_core.load_sections(sections);
-
-      // Convert the machine listing into a hash map
-      let registry_table = registry_core.get_table("mech/registry")?;
-      let registry_table_brrw = registry_table.borrow();
-      for row in 0..registry_table_brrw.rows {
-        let row_index = TableIndex::Index(row+1);
-        let name = registry_table_brrw.get_by_index(row_index.clone(), TableIndex::Alias(*NAME))?.as_string().unwrap().to_string();
-        let version = registry_table_brrw.get_by_index(row_index.clone(), TableIndex::Alias(*VERSION))?.as_string().unwrap().to_string();
-        let url = registry_table_brrw.get_by_index(row_index.clone(), TableIndex::Alias(*URL))?.as_string().unwrap().to_string();
-        self.machine_repository.insert(name, (version, url));
       }
+    };
+    Ok(registry_file)
+  }
+
+  // Downloads a registry and caches it at registry_path
+  fn download_registry(&self, registry: &str, registry_path: &Path, outgoing: &Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<String,MechError> {
+    // Download machine_repository index
+    match &outgoing {
+      Some(sender) => {sender.send(ClientMessage::String(format!("{} Updating machine registry from:\n{}", "[Downloading]".truecolor(153,221,85),registry)));}
+      None => (),
     }
-    // Resolve missing function errors
-    let mut resolved_errors = vec![];
-    {
-      let mut missing_functions: HashSet<u64> = HashSet::new();
-      for (error,eblocks) in &self.mech.errors {
-        match error {
-          MechErrorKind::MissingFunction(fxn_id) => {
-            missing_functions.insert(*fxn_id);
-          }
-          _ => (), // Other error, do nothing
+    // Download registry
+    let registry_url = registry;
+    let mut response_text = match reqwest::get(registry_url) {
+      Ok(ref response) if response.status() != reqwest::StatusCode::OK => {
+        return Err(MechError{msg: "".to_string(), id: 1247, kind: MechErrorKind::GenericError(format!("Can't download {}: {}", registry_url, response.status()))});
+      }
+      Ok(mut response) => {
+        match response.text() {
+          Ok(text) => {
+            text
+          },
+          Err(_) => {return Err(MechError{msg: "".to_string(), id: 1235, kind: MechErrorKind::None});},
         }
       }
-      for fxn_id in &self.mech.required_functions {
-        missing_functions.insert(*fxn_id);
+      Err(_) => {return Err(MechError{msg: "".to_string(), id: 1236, kind: MechErrorKind::None});},
+    };
+    // Save registry, moving it into place once it's written
+    let partial_path = registry_path.with_extension("mec.part");
+    let mut dest = match File::create(&partial_path) {
+      Ok(dest) => dest,
+      Err(_) => {return Err(MechError{msg: "".to_string(), id: 1237, kind: MechErrorKind::None});},
+    };
+    match dest.write_all(response_text.as_bytes()) {
+      Ok(dest) => dest,
+      Err(_) => {return Err(MechError{msg: "".to_string(), id: 1238, kind: MechErrorKind::None});},            
+    }
+    std::fs::rename(&partial_path, registry_path)?;
+    Ok(response_text)
+  }
+
+  // The program's lockfile, unless locking is off
+  pub fn lock_path(&self) -> Option<PathBuf> {
+    self.lock.path(&self.machine_dir)
+  }
+
+  // Locks the version of every machine the program has loaded
+  fn lock_machines(&self) -> Result<(),MechError> {
+    let lock_path = match self.lock_path() {
+      Some(lock_path) => lock_path,
+      None => return Ok(()),
+    };
+    let mut lockfile = Lockfile::read(&lock_path)?.unwrap_or_default();
+    let mut changed = false;
+    for (name, library) in &self.libraries {
+      let mut entry = match (library, self.machine_repository.get(name)) {
+        (Some(_), Some(entry)) => entry.clone(),
+        _ => continue,
+      };
+      if entry.sha256.is_none() {
+        let path = machine_path(&self.machine_dir, &entry.name, &entry.version, &machine_library(name));
+        entry.sha256 = Some(file_digest(&path)?);
       }
+      changed |= lockfile.insert(entry);
+    }
+    if changed {
+      lockfile.write(&lock_path)?;
+    }
+    Ok(())
+  }
 
-      for fxn_id in self.mech.functions.borrow().functions.keys() {
-        missing_functions.remove(fxn_id);
+  // Resolves every locked machine against the registry again, fetching the
+  // registry unless we're offline, and rewrites the lockfile. Returns the
+  // entries that changed. Machines the registry no longer lists stay locked.
+  pub fn update_lock(&mut self, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Vec<RegistryEntry>,MechError> {
+    let lock_path = match self.lock_path() {
+      Some(lock_path) => lock_path,
+      None => return Ok(vec![]),
+    };
+    self.registry_entries = self.read_registries(self.offline, outgoing)?;
+    let requirements = self.version_requirements()?;
+    let mut lockfile = Lockfile::read(&lock_path)?.unwrap_or_default();
+    let mut updated = vec![];
+    for locked in lockfile.machines.clone() {
+      let version_req = requirements.get(&locked.name).map(|(_, version_req)| version_req);
+      let mut entry = match self.registry_entries.get(&locked.name).and_then(|entries| select_version(entries, version_req)) {
+        Some(entry) => entry.clone(),
+        None => continue,
+      };
+      // Keep the digest we took from the library if it's the same one
+      if entry.sha256.is_none() && entry.version == locked.version && entry.url == locked.url {
+        entry.sha256 = locked.sha256.clone();
+      }
+      if lockfile.insert(entry.clone()) {
+        updated.push(entry);
       }
+    }
+    lockfile.write(&lock_path)?;
+    // Resolve against the new lock from now on
+    self.resolve_machines()?;
+    Ok(updated)
+  }
 
-      for fxn_id in missing_functions {
+  pub fn download_dependencies(&mut self, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Vec<MechErrorKind>,MechError> {
+    self.load_registry(outgoing.clone())?;
+    // Offline, every machine we need has to be here already
+    if self.offline {
+      let missing: Vec<String> = self.required_machines().iter()
+        .map(|name| &self.machine_repository[name])
+        .filter(|entry| !machine_available(&self.machine_dir, entry))
+        .map(|entry| format!("  {} v{} from {}", entry.name, entry.version, entry.url))
+        .collect();
+      if missing.len() > 0 {
+        return Err(MechError{msg: "".to_string(), id: 1240, kind: MechErrorKind::GenericError(format!("Offline, and these machines aren't in {:?} or at a local path:\n{}", self.machine_dir, missing.join("\n")))});
+      }
+    }
+    // Resolve missing function errors
+    let mut resolved_errors = vec![];
+    {
+      for fxn_id in self.missing_functions() {
         let fun_name = self.mech.dictionary.borrow().get(&fxn_id).unwrap().to_string();
         let m: Vec<_> = fun_name.split('/').collect();
         let m = m[0];
-        let underscore_name = m.replace("-","_");
-        #[cfg(target_os = "macos")]
-        let machine_name = format!("libmech_{}.dylib", underscore_name);
-        #[cfg(target_os = "linux")]
-        let machine_name = format!("libmech_{}.so", underscore_name);
-        #[cfg(target_os = "windows")]
-        let machine_name = format!("mech_{}.dll", underscore_name);
+        let machine_name = machine_library(m);
         match self.machine_repository.get(&m.to_string()) {
-          Some((ver, path)) => {
-            let library = self.libraries.entry(m.to_string()).or_insert_with(||{
-              match File::open(format!("machines/{}",machine_name)) {
-                Ok(_) => {
-                  match &outgoing {
-                    Some(sender) => {sender.send(ClientMessage::String(format!("{} {} v{}", "[Loading]".truecolor(153,221,85), m, ver)));}
-                    None => (),
-                  }
-                  let message = format!("Can't load library {:?}", machine_name);
-                  unsafe{Some(Library::new(format!("machines/{}",machine_name)).expect(&message))}
-                }
-                _ => Some(download_machine(&machine_name, m, path, ver, outgoing.clone()).unwrap())
-              }
-            });
+          Some(entry) => {
+            if !self.libraries.contains_key(m) {
+              let library = load_machine(&self.machine_dir, &machine_name, entry, self.offline, outgoing.clone())?;
+              self.libraries.insert(m.to_string(), Some(library));
+            }
+            let library = self.libraries.get_mut(m).unwrap();
             // Replace slashes with underscores and then add a null terminator
             let mut s = format!("{}\0", fun_name.replace("-","__").replace("/","_"));
             let error_msg = format!("Symbol {} not found",s);
@@ -317,21 +614,7 @@ impl Program {
       }
     }
     
-    // Dedupe needed ids
-    let needed_registers = self.mech.needed_registers();
-    let mut needed_tables = IndexSet::new();
-    for (needed_table_id,_,_) in needed_registers {
-      needed_tables.insert(needed_table_id.clone());
-    }
-    for (error,_) in &self.mech.errors {
-      match error {
-        MechErrorKind::MissingTable(table_id) => {
-          needed_tables.insert(table_id.clone());
-        }
-        _ => (),
-      }
-    }
-
+    let needed_tables = self.needed_tables();
     let mut machine_init_code = vec![];
     for needed_table_id in needed_tables.iter() {
       let dictionary = self.mech.dictionary.borrow();
@@ -341,32 +624,19 @@ impl Program {
       match self.loaded_machines.contains(&needed_machine_id) {
         false => {
           self.loaded_machines.insert(needed_machine_id);
-          #[cfg(target_os = "macos")]
-          let machine_name = format!("libmech_{}.dylib", m[0]);
-          #[cfg(target_os = "linux")]
-          let machine_name = format!("libmech_{}.so", m[0]);
-          #[cfg(target_os = "windows")]
-          let machine_name = format!("mech_{}.dll", m[0]);
+          let machine_name = machine_library(m[0]);
           match self.machine_repository.get(m[0]) {
-            Some((ver, path)) => {
-              let library = self.libraries.entry(m[0].to_string()).or_insert_with(||{
-                match File::open(format!("machines/{}",machine_name)) {
-                  Ok(_) => {
-                    match &outgoing {
-                      Some(sender) => {sender.send(ClientMessage::String(format!("{} {} v{}", "[Loading]".truecolor(153,221,85), m[0], ver)));}
-                      None => (),
-                    }
-                    let message = format!("Can't load library {:?}", machine_name);
-                    unsafe{Some(Library::new(format!("machines/{}",machine_name)).expect(&message))}
-                  }
-                  _ => {
-                    match download_machine(&machine_name, m[0], path, ver, outgoing.clone()) {
-                      Ok(library) => Some(library),
-                      Err(err) => None,
-                    }
-                  }
-                }
-              });          
+            Some(entry) => {
+              if !self.libraries.contains_key(m[0]) {
+                // A library that doesn't match its digest is never loaded
+                let library = match load_machine(&self.machine_dir, &machine_name, entry, self.offline, outgoing.clone()) {
+                  Ok(library) => Some(library),
+                  Err(err) if err.id == 1275 => return Err(err),
+                  Err(err) => None,
+                };
+                self.libraries.insert(m[0].to_string(), library);
+              }
+              let library = self.libraries.get_mut(m[0]).unwrap();          
               // Replace slashes with underscores and then add a null terminator
               let mut s = format!("{}\0", needed_table_name.replace("-","__").replace("/","_"));
               let error_msg = format!("Symbol {} not found",s);
@@ -452,9 +722,128 @@ impl Program {
       }
     }*/
     
+    // The machines are loaded either way, so a lockfile we can't write is
+    // only worth a warning
+    if let Err(err) = self.lock_machines() {
+      match &outgoing {
+        Some(sender) => {sender.send(ClientMessage::String(format!("{} Can't update the lockfile {:?}: {:?}", "[Warning]".truecolor(246,192,78), self.lock_path().unwrap(), err.kind)));}
+        None => (),
+      }
+    }
     Ok(resolved_errors)
   }
 
+  // Functions the program calls that no machine has provided yet
+  fn missing_functions(&self) -> HashSet<u64> {
+    let mut missing_functions: HashSet<u64> = HashSet::new();
+    for (error,eblocks) in &self.mech.errors {
+      match error {
+        MechErrorKind::MissingFunction(fxn_id) => {
+          missing_functions.insert(*fxn_id);
+        }
+        _ => (), // Other error, do nothing
+      }
+    }
+    for fxn_id in &self.mech.required_functions {
+      missing_functions.insert(*fxn_id);
+    }
+    for fxn_id in self.mech.functions.borrow().functions.keys() {
+      missing_functions.remove(fxn_id);
+    }
+    missing_functions
+  }
+
+  // Tables the program reads that don't exist yet, deduped
+  fn needed_tables(&self) -> IndexSet<TableId> {
+    let needed_registers = self.mech.needed_registers();
+    let mut needed_tables = IndexSet::new();
+    for (needed_table_id,_,_) in needed_registers {
+      needed_tables.insert(needed_table_id.clone());
+    }
+    for (error,_) in &self.mech.errors {
+      match error {
+        MechErrorKind::MissingTable(table_id) => {
+          needed_tables.insert(table_id.clone());
+        }
+        _ => (),
+      }
+    }
+    needed_tables
+  }
+
+  // The registry's machines that provide the functions and tables the
+  // program is missing, and haven't been loaded yet
+  pub fn required_machines(&self) -> IndexSet<String> {
+    let dictionary = self.mech.dictionary.borrow();
+    let mut names = vec![];
+    for fxn_id in self.missing_functions() {
+      if let Some(fun_name) = dictionary.get(&fxn_id) {
+        names.push(fun_name.to_string().split('/').next().unwrap().to_string());
+      }
+    }
+    for needed_table_id in self.needed_tables() {
+      if let Some(table_name) = dictionary.get(needed_table_id.unwrap()) {
+        let m = table_name.to_string().split('/').next().unwrap().to_string();
+        if !self.loaded_machines.contains(&hash_str(&m)) {
+          names.push(m);
+        }
+      }
+    }
+    names.into_iter()
+      .filter(|name| self.machine_repository.contains_key(name) && !self.libraries.contains_key(name))
+      .collect()
+  }
+
+  // Caches the registry and every machine the program needs in the machine
+  // directory without loading them, and returns the machines. The registries
+  // are read again, unless we're offline, so copies left by an earlier
+  // vendor don't decide the versions.
+  pub fn vendor(&mut self, outgoing: Option<crossbeam_channel::Sender<ClientMessage>>) -> Result<Vec<RegistryEntry>,MechError> {
+    self.registry_entries = self.read_registries(self.offline, outgoing.clone())?;
+    self.resolve_machines()?;
+    // Local registries are read where they are, so they have to be copied.
+    // Any copy from an earlier vendor is replaced, since it might not list
+    // the versions we're about to cache.
+    for (ix, registry) in self.registries.iter().enumerate() {
+      let registry_path = self.registry_cache_path(ix);
+      if !is_remote(registry) && local_path(registry).is_file() {
+        std::fs::copy(local_path(registry), &registry_path)?;
+      }
+    }
+    let mut vendored = vec![];
+    for name in self.required_machines() {
+      let entry = self.machine_repository[&name].clone();
+      let library = machine_library(&name);
+      let path = machine_path(&self.machine_dir, &entry.name, &entry.version, &library);
+      if path.is_file() {
+        verify_machine(&path, &entry)?;
+      } else {
+        cache_machine(&self.machine_dir, &library, &entry, self.offline, outgoing.clone())?;
+      }
+      vendored.push(entry);
+    }
+    Ok(vendored)
+  }
+
+  // Every version of every machine in this program's machine directory
+  pub fn cached_machines(&self) -> Result<Vec<CachedMachine>,MechError> {
+    cached_machines(&self.machine_dir)
+  }
+
+  // Removes cached versions other than the ones the program resolved to, and
+  // returns them. Nothing is removed before the registry is loaded.
+  pub fn prune_machines(&self) -> Result<Vec<CachedMachine>,MechError> {
+    if self.machine_repository.len() == 0 {
+      return Ok(vec![]);
+    }
+    prune_machines(&self.machine_dir, |machine| {
+      match self.machine_repository.get(&machine.name) {
+        Some(entry) => entry.version == machine.version,
+        None => false,
+      }
+    })
+  }
+
   /*pub fn clear(&mut self) {
     self.mech.clear();
   }*/
@@ -12,20 +12,24 @@ use crossbeam_channel::Receiver;
 use colored::*;
 
 use super::program::Program;
-use super::persister::Persister;
+use super::machines::{default_machine_dir, DEFAULT_REGISTRY, DEFAULT_REGISTRY_TTL, VersionChange, RegistryEntry, LockLocation};
+use super::migration::Migration;
+use super::persister::{Persister, PersisterMessage, PersisterOptions, FileBackend, LogEnd, change_table_id, segment_path};
 
 use std::net::{SocketAddr, UdpSocket};
 extern crate websocket;
 use websocket::OwnedMessage;
 
 use std::io;
-use std::time::Instant;
+use std::time::{Instant, Duration};
 use std::sync::Mutex;
+use std::collections::VecDeque;
 
 extern crate miniz_oxide;
 extern crate bincode;
 use std::io::{Write, BufReader, BufWriter, stdout};
 use std::fs::{OpenOptions, File, canonicalize, create_dir};
+use std::path::{Path, PathBuf};
 
 use miniz_oxide::inflate::decompress_to_vec;
 use miniz_oxide::deflate::compress_to_vec;
@@ -72,6 +76,27 @@ pub enum ClientMessage {
   StepDone,
   Done,
   Ready,
+  Queued(usize), // Transactions waiting for the paused run loop to resume
+  Dropped(Transaction), // A transaction that didn't fit in the paused run loop's queue, or was still in it when the run loop stopped
+  RegistryRefreshed(Vec<VersionChange>), // Machines whose version changed when the registry was refreshed
+  Machines(Vec<RegistryEntry>), // Registry entries that matched a search
+}
+
+// What to do with a transaction that arrives while the run loop is paused
+// and its queue is already full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOverflow {
+  DropOldest,
+  DropNewest,
+}
+
+// Requests for what the run loop does beyond RunLoopMessage. They're sent
+// with the RunLoop method of the same name.
+#[derive(Debug, Clone)]
+pub enum ControlMessage {
+  Compact,
+  RefreshRegistry,
+  ListMachines(String),
 }
 
 pub struct RunLoop {
@@ -80,6 +105,7 @@ pub struct RunLoop {
   thread: JoinHandle<()>,
   pub outgoing: Sender<RunLoopMessage>,
   pub incoming: Receiver<ClientMessage>,
+  pub control: Sender<ControlMessage>,
 }
 
 impl RunLoop {
@@ -102,6 +128,29 @@ impl RunLoop {
     }
   }
 
+  fn send_control(&self, msg: ControlMessage) -> Result<(),&str> {
+    match self.control.send(msg) {
+      Ok(_) => Ok(()),
+      Err(_) => Err("Failed to send message"),
+    }
+  }
+
+  // Snapshot the persisted tables and drop the log the snapshot covers
+  pub fn compact(&self) -> Result<(),&str> {
+    self.send_control(ControlMessage::Compact)
+  }
+
+  // Fetch the registries again. The run loop replies with RegistryRefreshed.
+  pub fn refresh_registry(&self) -> Result<(),&str> {
+    self.send_control(ControlMessage::RefreshRegistry)
+  }
+
+  // Search the registries' machines by name and description. The run loop
+  // replies with Machines, and an empty query lists them all.
+  pub fn list_machines(&self, query: &str) -> Result<(),&str> {
+    self.send_control(ControlMessage::ListMachines(query.to_string()))
+  }
+
   pub fn receive(&self) -> Result<ClientMessage,&str> {
     match self.incoming.recv() {
       Ok(message) => Ok(message),
@@ -119,31 +168,51 @@ impl RunLoop {
 
 }
 
+// Minify the core the same way DumpCore does, keeping only the given tables
+fn snapshot_tables(core: &Core, table_ids: &HashSet<u64>) -> Vec<MiniTable> {
+  MiniCore::minify_core(core).database.into_iter().filter(|table| table_ids.contains(&table.id)).collect()
+}
+
 // ## Program Runner
 
+// Tells the persister the names of tables it hasn't been told yet, so
+// prefix patterns can match them. A table the core doesn't know the name of
+// yet is tried again next time, since replayed tables are only named once
+// the code that uses them is loaded.
+fn name_tables(persister: &Persister, core: &Core, table_ids: impl Iterator<Item=u64>, named_tables: &mut HashSet<u64>) -> Result<(),MechError> {
+  let dictionary = core.dictionary.borrow();
+  let names: Vec<(u64,String)> = table_ids
+    .filter(|id| !named_tables.contains(id))
+    .filter_map(|id| dictionary.get(&id).map(|name| (id, name.to_string())))
+    .collect();
+  if names.is_empty() {
+    return Ok(());
+  }
+  named_tables.extend(names.iter().map(|(id,_)| *id));
+  persister.name_tables(names.into_iter().map(|(_,name)| name).collect())
+}
+
 pub struct ProgramRunner {
   pub name: String,
   pub socket: Option<Arc<UdpSocket>>,
-  pub registry: String,
-  //pub persistence_channel: Option<Sender<PersisterMessage>>,
+  pub registries: Vec<String>, // Where machines are listed, in order of precedence
+  pub registry_ttl: Option<Duration>, // How long a downloaded registry is used before it's fetched again
+  pub machine_dir: PathBuf, // Where downloaded machines are cached
+  pub offline: bool, // Never go to the network for the registry or machines
+  pub lock: LockLocation, // Where the program's machine versions are locked
+  pub persistence_path: Option<String>,
+  pub persistence_channel: Option<Sender<PersisterMessage>>,
+  pub compaction_threshold: Option<u64>, // Compact the log once it grows past this many bytes
+  pub persister_options: PersisterOptions, // Fsync, rotation, and which tables get persisted
+  pub migration: Migration, // Applied to persisted changes as they are replayed
+  pub history_limit: usize, // How many transactions we can step back over
+  pub pause_queue_limit: usize, // How many transactions to hold while paused
+  pub pause_queue_overflow: QueueOverflow,
 }
 
 impl ProgramRunner {
 
   pub fn new(name:&str) -> ProgramRunner {
-    // Start a persister
-    /*
-    let persist_name = format!("{}.mdb", name);
-    let mut persister = Persister::new(&persist_name);
-    persister.load(&persist_name);
-    let changes = persister.get_changes();
-
-    // Load database
-    ////println!("{} Applying {} stored changes...", BrightCyan.paint(format!("[{}]", name)), changes.len());    
-    for change in changes {
-      program.mech.process_transaction(&Transaction::from_change(change));
-    }*/
-    
     let socket = match UdpSocket::bind("127.0.0.1:0") {
       Ok(socket) => Some(Arc::new(socket)),
       _ => None,
@@ -152,14 +221,29 @@ impl ProgramRunner {
     ProgramRunner {
       name: name.to_owned(),
       socket,
-      registry: "https://gitlab.com/mech-lang/machines/mech/-/raw/v0.1-beta/src/registry.mec".to_string(),
-      //program,
-      // TODO Use the persistence file specified by the user
-      //persistence_channel: Some(persister.get_channel()),
-      //persistence_channel: None,
+      registries: vec![DEFAULT_REGISTRY.to_string()],
+      registry_ttl: Some(DEFAULT_REGISTRY_TTL),
+      machine_dir: default_machine_dir(),
+      offline: false,
+      lock: LockLocation::MachineDir,
+      persistence_path: None,
+      persistence_channel: None,
+      compaction_threshold: None,
+      persister_options: PersisterOptions::default(),
+      migration: Migration::new(),
+      history_limit: 1000,
+      pause_queue_limit: 10_000,
+      pause_queue_overflow: QueueOverflow::DropOldest,
     }
   }
 
+  // Persist every applied transaction to <name>.mdb. Anything already in
+  // the file is replayed into the program before it reports Ready.
+  pub fn persist(&mut self, name: &str) {
+    let path = Path::new(name).with_extension("mdb");
+    self.persistence_path = Some(path.to_string_lossy().to_string());
+  }
+
   /*pub fn load_program(&mut self, input: String) -> Result<(),Box<std::error::Error>> {
     self.program.compile_program(input);
     Ok(())
@@ -179,7 +263,7 @@ impl ProgramRunner {
   }*/
 
   pub fn add_persist_channel(&mut self, persister:&mut Persister) {
-    //self.persistence_channel = Some(persister.get_channel());
+    self.persistence_channel = Some(persister.get_channel());
   }
 
   pub fn run(self) -> Result<RunLoop,MechError> {
@@ -188,8 +272,16 @@ impl ProgramRunner {
     let (outgoing, program_incoming) = crossbeam_channel::unbounded();
     let runloop_outgoing = outgoing.clone();
     let (client_outgoing, incoming) = crossbeam_channel::unbounded();
+    let (control, mut control_incoming) = crossbeam_channel::unbounded();
     //let mut program = self.program;
-    //let persistence_channel = self.persistence_channel;
+    let persistence_path = self.persistence_path.clone();
+    let persistence_channel = self.persistence_channel.clone();
+    let compaction_threshold = self.compaction_threshold;
+    let persister_options = self.persister_options.clone();
+    let migration = self.migration.clone();
+    let history_limit = self.history_limit;
+    let pause_queue_limit = self.pause_queue_limit;
+    let pause_queue_overflow = self.pause_queue_overflow;
 
     let name = format!("{}", &self.name.clone());
     let socket_address = match self.socket {
@@ -200,7 +292,13 @@ impl ProgramRunner {
     // Start a channel receiving thread    
     let thread = thread::Builder::new().name(name.clone()).spawn(move || {
       
-      let mut program = Program::new("new program", 100, 1000, outgoing.clone(), program_incoming, self.registry);
+      let mut program = Program::new("new program", 100, 1000, outgoing.clone(), program_incoming, DEFAULT_REGISTRY.to_string());
+      program.registries = self.registries.clone();
+      program.registry_ttl = self.registry_ttl;
+      program.history_limit = history_limit;
+      program.machine_dir = self.machine_dir.clone();
+      program.offline = self.offline;
+      program.lock = self.lock.clone();
 
       let program_channel_udpsocket = program.outgoing.clone();
       let program_channel_udpsocket = program.outgoing.clone();
@@ -266,6 +364,52 @@ impl ProgramRunner {
         }
       }
 
+      // Replay the persisted database
+      let mut persisted_tables: HashSet<u64> = HashSet::new();
+      let mut named_tables: HashSet<u64> = HashSet::new();
+      // A compaction prunes every segment before the current one, so after a
+      // corrupt record it would delete the intact records we couldn't load
+      let mut corrupt_log = false;
+      let mut persister = match persistence_path {
+        Some(ref path) => {
+          let transactions = match Persister::load(&FileBackend::new(path)) {
+            Ok((transactions, LogEnd::Clean)) => transactions,
+            Ok((transactions, LogEnd::TornTail(position))) => {
+              client_outgoing.send(ClientMessage::String(format!("{} {} ends with a partially written record at byte {}. It will be discarded.", "[Warning]".truecolor(246,192,78), segment_path(path, position.segment), position.offset)));
+              transactions
+            }
+            Ok((transactions, LogEnd::Corrupt(position))) => {
+              client_outgoing.send(ClientMessage::Error(MechError{msg: "".to_string(), id: 1304, kind: MechErrorKind::GenericError(format!("{} is corrupt at byte {}. Only the records before it were loaded, and the log won't be compacted until it's repaired.", segment_path(path, position.segment), position.offset))}));
+              corrupt_log = true;
+              transactions
+            }
+            Err(err) => {
+              client_outgoing.send(ClientMessage::Error(err));
+              vec![]
+            }
+          };
+          client_outgoing.send(ClientMessage::String(format!("{} {} stored transactions from {}", "[Loading]".truecolor(153,221,85), transactions.len(), path)));
+          let mut replay = migration.replay();
+          for txn in &transactions {
+            let result = replay.migrate(txn, &program.mech).and_then(|txn| {
+              persisted_tables.extend(txn.iter().map(change_table_id));
+              program.mech.process_transaction(&txn)
+            });
+            match result {
+              Err(err) => {client_outgoing.send(ClientMessage::Error(err));}
+              _ => (),
+            }
+          }
+          let persister = Persister::with_options(FileBackend::new(path), persister_options);
+          // Replayed tables go into the next snapshot, so the policy has to be able to match them
+          if let Err(err) = name_tables(&persister, &program.mech, persisted_tables.iter().cloned(), &mut named_tables) {
+            client_outgoing.send(ClientMessage::Error(err));
+          }
+          Some(persister)
+        }
+        None => None,
+      };
+
       // Step cores
       /*program.mech.step();
       for core in program.cores.values_mut() {
@@ -276,13 +420,97 @@ impl ProgramRunner {
       client_outgoing.send(ClientMessage::Ready);
       let mut paused = false;
       let mut iteration: u64 = 0;
+      let mut paused_queue: VecDeque<Transaction> = VecDeque::new();
+      let mut release_queued = false; // Apply one queued transaction even though we're paused
       'runloop: loop {
-        match (program.incoming.recv(), paused) {
+        // A queued transaction released by StepForward is answered like a step through history
+        let stepping = release_queued;
+        // Transactions queued while paused go before anything new
+        let next = if paused_queue.len() > 0 && (!paused || release_queued) {
+          release_queued = false;
+          let txn = paused_queue.pop_front().unwrap();
+          client_outgoing.send(ClientMessage::Queued(paused_queue.len()));
+          (Ok(RunLoopMessage::Transaction(txn)), false)
+        } else {
+          select! {
+            recv(program.incoming) -> message => (message, paused),
+            recv(control_incoming) -> message => {
+              match message {
+                Ok(ControlMessage::Compact) => {
+                  match persister {
+                    // The snapshot would drop the transactions we stepped back over
+                    Some(_) if program.offset > 0 => {
+                      client_outgoing.send(ClientMessage::Error(MechError{msg: "".to_string(), id: 1317, kind: MechErrorKind::GenericError(format!("Can't compact while {} transactions are stepped back over. Step forward or resume first.", program.offset))}));
+                    }
+                    Some(_) if corrupt_log => {
+                      client_outgoing.send(ClientMessage::Error(MechError{msg: "".to_string(), id: 1319, kind: MechErrorKind::GenericError("Can't compact a log that was corrupt when it was loaded. Compacting would delete the records after the corrupt one. Repair the log and restart first.".to_string())}));
+                    }
+                    Some(ref persister) => {
+                      // Wait for the snapshot to land so errors are reported with this message
+                      let result = name_tables(persister, &program.mech, persisted_tables.iter().cloned(), &mut named_tables)
+                        .and_then(|_| persister.snapshot(snapshot_tables(&program.mech, &persisted_tables)))
+                        .and_then(|_| persister.flush());
+                      let errors: Vec<MechError> = result.err().into_iter().chain(persister.errors()).collect();
+                      if errors.is_empty() {
+                        client_outgoing.send(ClientMessage::String(format!("Compacted {} tables", persisted_tables.len())));
+                      }
+                      for err in errors {
+                        client_outgoing.send(ClientMessage::Error(err));
+                      }
+                    }
+                    None => {
+                      client_outgoing.send(ClientMessage::String("Nothing to compact, persistence is off.".to_string()));
+                    }
+                  }
+                }
+                Ok(ControlMessage::RefreshRegistry) => {
+                  match program.refresh_registry(Some(client_outgoing.clone())) {
+                    Ok(changes) => {client_outgoing.send(ClientMessage::RegistryRefreshed(changes));}
+                    Err(err) => {client_outgoing.send(ClientMessage::Error(err));}
+                  }
+                }
+                Ok(ControlMessage::ListMachines(query)) => {
+                  match program.search_machines(&query, Some(client_outgoing.clone())) {
+                    Ok(machines) => {client_outgoing.send(ClientMessage::Machines(machines));}
+                    Err(err) => {client_outgoing.send(ClientMessage::Error(err));}
+                  }
+                }
+                // Nothing can be sent once the RunLoop is dropped, but
+                // machines can still send RunLoopMessages
+                Err(_) => {
+                  control_incoming = crossbeam_channel::never();
+                  continue 'runloop;
+                }
+              }
+              client_outgoing.send(ClientMessage::Done);
+              continue 'runloop;
+            }
+          }
+        };
+        match next {
           (Ok(RunLoopMessage::Transaction(txn)), false) => {
             // Process the transaction and calculate how long it took. 
             let now = Instant::now();
+            let inverse = program.invert_transaction(&txn);
             match program.mech.process_transaction(&txn) {
               Ok((new_block_ids,changed_registers)) => {
+                program.record_transaction(txn.clone(), inverse);
+                // Persist the applied transaction
+                if let Some(ref persister) = persister {
+                  let mut result = name_tables(persister, &program.mech, txn.iter().map(change_table_id), &mut named_tables);
+                  persisted_tables.extend(txn.iter().map(change_table_id));
+                  result = result.and_then(|_| persister.send(txn.clone()));
+                  if result.is_ok() && !corrupt_log && compaction_threshold.map_or(false, |threshold| persister.log_size() > threshold) {
+                    result = name_tables(persister, &program.mech, persisted_tables.iter().cloned(), &mut named_tables)
+                      .and_then(|_| persister.snapshot(snapshot_tables(&program.mech, &persisted_tables)));
+                  }
+                  for err in result.err().into_iter().chain(persister.errors()) {
+                    client_outgoing.send(ClientMessage::Error(err));
+                  }
+                }
+                if let Some(ref channel) = persistence_channel {
+                  channel.send(PersisterMessage::Write(txn.clone()));
+                }
                 for trigger_register in &changed_registers {                  
                   // Handle machines first
                   let mut machine_triggers = vec![];
@@ -391,6 +619,9 @@ impl ProgramRunner {
             let cycle_duration = elapsed_time.as_nanos() as f64;
             client_outgoing.send(ClientMessage::Timing(1.0 / (cycle_duration / 1_000_000_000.0)));
             client_outgoing.send(ClientMessage::StepDone);
+            if stepping {
+              client_outgoing.send(ClientMessage::Time(program.offset));
+            }
           },
           (Ok(RunLoopMessage::Listening((core_id, register))), _) => {
             let (table_id,row,col) = &register;
@@ -745,6 +976,18 @@ impl ProgramRunner {
             client_outgoing.send(ClientMessage::StepDone);
           }
           (Ok(RunLoopMessage::Stop), _) => { 
+            // Transactions queued while paused are never applied
+            for txn in paused_queue.drain(..) {
+              client_outgoing.send(ClientMessage::Dropped(txn));
+            }
+            // Make sure everything is on disk before telling the client we stopped
+            if let Some(persister) = persister.take() {
+              persister.close();
+              for err in persister.get_status_channel().iter() {
+                client_outgoing.send(ClientMessage::Error(err));
+              }
+              persister.wait();
+            }
             client_outgoing.send(ClientMessage::Stop);
             break 'runloop;
           },
@@ -766,19 +1009,48 @@ impl ProgramRunner {
           },
           (Ok(RunLoopMessage::Resume), true) => {
             paused = false;
-            //program.mech.resume();
+            // Return to the present before taking new transactions
+            while program.offset > 0 {
+              if let Err(err) = program.step_forward_one() {
+                client_outgoing.send(ClientMessage::Error(err));
+                break;
+              }
+            }
             client_outgoing.send(ClientMessage::Resume);
           },
           (Ok(RunLoopMessage::StepBack), _) => {
             if !paused {
               paused = true;
+              client_outgoing.send(ClientMessage::Pause);
+            }
+            match program.step_back_one() {
+              Ok(offset) => {client_outgoing.send(ClientMessage::Time(offset));}
+              Err(err) => {client_outgoing.send(ClientMessage::Error(err));}
             }
-            //program.mech.step_back_one();
-            //client_outgoing.send(ClientMessage::Time(program.mech.offset));
+          }
+          (Ok(RunLoopMessage::Transaction(txn)), true) => {
+            paused_queue.push_back(txn);
+            if paused_queue.len() > pause_queue_limit {
+              let dropped = match pause_queue_overflow {
+                QueueOverflow::DropOldest => paused_queue.pop_front(),
+                QueueOverflow::DropNewest => paused_queue.pop_back(),
+              };
+              if let Some(txn) = dropped {
+                client_outgoing.send(ClientMessage::Dropped(txn));
+              }
+            }
+            client_outgoing.send(ClientMessage::Queued(paused_queue.len()));
           }
           (Ok(RunLoopMessage::StepForward), true) => {
-            //program.mech.step_forward_one();
-            //client_outgoing.send(ClientMessage::Time(program.mech.offset));
+            // Step through history first, then through what queued up while paused
+            if program.offset == 0 && paused_queue.len() > 0 {
+              release_queued = true;
+              continue 'runloop;
+            }
+            match program.step_forward_one() {
+              Ok(offset) => {client_outgoing.send(ClientMessage::Time(offset));}
+              Err(err) => {client_outgoing.send(ClientMessage::Error(err));}
+            }
           } 
           (Err(_), _) => {
             break 'runloop
@@ -787,12 +1059,16 @@ impl ProgramRunner {
         }
         client_outgoing.send(ClientMessage::Done);
       }
-      /*if let Some(channel) = persistence_channel {
+      if let Some(persister) = persister {
+        persister.close();
+        persister.wait();
+      }
+      if let Some(channel) = persistence_channel {
         channel.send(PersisterMessage::Stop);
-      }*/
+      }
     }).unwrap();
 
-    Ok(RunLoop { name, socket_address, thread, outgoing: runloop_outgoing, incoming })
+    Ok(RunLoop { name, socket_address, thread, outgoing: runloop_outgoing, incoming, control })
   }
 
   /*pub fn colored_name(&self) -> term_painter::Painted<String> {
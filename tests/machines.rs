@@ -0,0 +1,497 @@
+extern crate mech_program;
+extern crate mech_core;
+extern crate crossbeam_channel;
+use mech_program::*;
+use mech_core::*;
+use std::path::PathBuf;
+use std::ffi::OsString;
+use std::collections::HashMap;
+
+fn temp_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("mech-machines-{}-{}", name, std::process::id()));
+  let _ = std::fs::remove_dir_all(&dir);
+  dir
+}
+
+#[test]
+fn machine_dir_from_env() {
+  let resolve = |vars: &[(&str, &str)]| {
+    let vars: HashMap<String, OsString> = vars.iter().map(|(name, value)| (name.to_string(), OsString::from(value))).collect();
+    resolve_machine_dir(&|name| vars.get(name).cloned())
+  };
+  assert_eq!(resolve(&[(MACHINE_DIR_VAR, "/srv/machines"), ("HOME", "/home/mech")]), PathBuf::from("/srv/machines"));
+  // An empty override is ignored
+  let home = resolve(&[(MACHINE_DIR_VAR, ""), ("HOME", "/home/mech"), ("LOCALAPPDATA", "/home/mech")]);
+  assert!(home.starts_with("/home/mech") && home.ends_with("mech/machines"));
+  assert_eq!(resolve(&[]), PathBuf::from("machines"));
+}
+
+#[test]
+fn download_into_machine_dir() {
+  let source = temp_dir("source");
+  std::fs::create_dir_all(&source).unwrap();
+  std::fs::write(source.join("libmech_test.so"), b"not a library").unwrap();
+  let machine_dir = temp_dir("cache").join("nested");
+  // The copy isn't a real library, so loading it fails after it's cached
+  let entry = RegistryEntry{name: "test".to_string(), version: "0.0.1".to_string(), url: format!("{}/", source.display()), sha256: None, description: None, mirrors: vec![]};
+  let result = download_machine(&machine_dir, "libmech_test.so", &entry, false, None);
+  assert!(result.is_err());
+  assert_eq!(std::fs::read(machine_dir.join("test").join("0.0.1").join("libmech_test.so")).unwrap(), b"not a library");
+}
+
+#[test]
+fn list_and_prune_versions() {
+  let machine_dir = temp_dir("versions");
+  for (name, version) in &[("math", "0.1.0"), ("math", "0.1.1"), ("io", "0.2.0")] {
+    let path = machine_path(&machine_dir, name, version, "libmech.so");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, b"").unwrap();
+  }
+  std::fs::write(machine_dir.join("registry.mec"), b"").unwrap();
+  let versions = |machines: Vec<CachedMachine>| machines.into_iter().map(|m| (m.name, m.version)).collect::<Vec<_>>();
+  assert_eq!(versions(cached_machines(&machine_dir).unwrap()), vec![
+    ("io".to_string(), "0.2.0".to_string()),
+    ("math".to_string(), "0.1.0".to_string()),
+    ("math".to_string(), "0.1.1".to_string()),
+  ]);
+  let pruned = prune_machines(&machine_dir, |m| m.name == "math" && m.version == "0.1.1").unwrap();
+  assert_eq!(versions(pruned), vec![
+    ("io".to_string(), "0.2.0".to_string()),
+    ("math".to_string(), "0.1.0".to_string()),
+  ]);
+  assert_eq!(versions(cached_machines(&machine_dir).unwrap()), vec![("math".to_string(), "0.1.1".to_string())]);
+  assert!(!machine_dir.join("io").exists());
+  assert!(machine_dir.join("registry.mec").exists());
+  assert!(cached_machines(&machine_dir.join("missing")).unwrap().is_empty());
+}
+
+#[test]
+fn registry_digests() {
+  let registry = read_registry(r#"
+Registry
+=========
+
+  #mech/registry = [|name version url sha256|
+    "math" "0.1.0" "https://example.com/math" "ABC123"
+    "io" "0.2.0" "https://example.com/io" ""]"#).unwrap();
+  assert_eq!(registry["math"][0].sha256, Some("ABC123".to_string()));
+  assert_eq!(registry["io"][0].sha256, None);
+  let registry = read_registry(r#"
+Registry
+=========
+
+  #mech/registry = [|name version url|
+    "math" "0.1.0" "https://example.com/math"]"#).unwrap();
+  assert_eq!(registry["math"][0], RegistryEntry{name: "math".to_string(), version: "0.1.0".to_string(), url: "https://example.com/math".to_string(), sha256: None, description: None, mirrors: vec![]});
+}
+
+#[test]
+fn registry_paths_stay_in_the_machine_dir() {
+  let registry = |name: &str, version: &str| read_registry(&format!("\nRegistry\n=========\n\n  #mech/registry = [|name version url|\n    {:?} {:?} \"https://example.com/math\"]", name, version)).map_err(|err| err.id);
+  assert!(registry("math", "0.1.0").is_ok());
+  for (name, version) in &[("../../x", "0.1.0"), ("math", "../x"), ("a/b", "0.1.0"), ("math", ".."), ("", "0.1.0"), ("math", "C:x")] {
+    assert_eq!(registry(name, version).unwrap_err(), 1280);
+  }
+  let dir = temp_dir("lock-paths");
+  std::fs::create_dir_all(&dir).unwrap();
+  let mut lockfile = Lockfile::new();
+  lockfile.insert(RegistryEntry{name: "..".to_string(), version: "0.1.0".to_string(), url: "https://example.com/math".to_string(), sha256: None, description: None, mirrors: vec![]});
+  lockfile.write(&dir.join("mech.lock")).unwrap();
+  assert_eq!(Lockfile::read(&dir.join("mech.lock")).unwrap_err().id, 1280);
+}
+
+#[test]
+fn refuse_mismatched_digest() {
+  let source = temp_dir("digest-source");
+  std::fs::create_dir_all(&source).unwrap();
+  std::fs::write(source.join("libmech_test.so"), b"not a library").unwrap();
+  let digest = file_digest(&source.join("libmech_test.so")).unwrap();
+  assert_eq!(digest.len(), 64);
+  let machine_dir = temp_dir("digest-cache");
+  let mut entry = RegistryEntry{name: "test".to_string(), version: "0.0.1".to_string(), url: format!("{}/", source.display()), sha256: Some("0".repeat(64)), description: None, mirrors: vec![]};
+  // A download that doesn't match isn't kept
+  match download_machine(&machine_dir, "libmech_test.so", &entry, false, None) {
+    Err(MechError{id: 1275, kind: MechErrorKind::GenericError(message), ..}) => {
+      assert!(message.ends_with(&format!("has SHA-256 digest {}, but the registry lists {}", digest, "0".repeat(64))));
+    }
+    result => panic!("{:?}", result.err()),
+  }
+  let cached = machine_path(&machine_dir, "test", "0.0.1", "libmech_test.so");
+  assert!(!cached.exists());
+  // Neither is one that changed after it was cached
+  entry.sha256 = Some(digest.to_uppercase());
+  assert!(verify_machine(&cached.with_file_name("missing"), &entry).is_err());
+  std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+  std::fs::write(&cached, b"not a library").unwrap();
+  assert!(verify_machine(&cached, &entry).is_ok());
+  std::fs::write(&cached, b"not a librar").unwrap();
+  match load_machine(&machine_dir, "libmech_test.so", &entry, false, None) {
+    Err(MechError{id: 1275, ..}) => (),
+    result => panic!("{:?}", result.err()),
+  }
+}
+
+#[test]
+fn failed_download_leaves_nothing() {
+  let source = temp_dir("partial-source");
+  std::fs::create_dir_all(&source).unwrap();
+  let machine_dir = temp_dir("partial-cache");
+  let cached = machine_path(&machine_dir, "test", "0.0.1", "libmech_test.so");
+  let partial = cached.with_file_name("libmech_test.so.part");
+  // Left behind by an interrupted download
+  std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+  std::fs::write(&partial, b"not a").unwrap();
+  let entry = RegistryEntry{name: "test".to_string(), version: "0.0.1".to_string(), url: format!("{}/", source.display()), sha256: None, description: None, mirrors: vec![]};
+  assert!(download_machine(&machine_dir, "libmech_test.so", &entry, false, None).is_err());
+  assert!(!cached.exists());
+  assert!(!partial.exists());
+  // The next download starts over and moves the whole file into place
+  std::fs::write(source.join("libmech_test.so"), b"not a library").unwrap();
+  assert!(download_machine(&machine_dir, "libmech_test.so", &entry, false, None).is_err());
+  assert_eq!(std::fs::read(&cached).unwrap(), b"not a library");
+  assert!(!partial.exists());
+}
+
+fn offline_program(machine_dir: &std::path::Path, registry: &str) -> Program {
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let mut program = Program::new("test", 100, 1000, outgoing, incoming, registry.to_string());
+  program.machine_dir = machine_dir.to_path_buf();
+  program.offline = true;
+  program
+}
+
+#[test]
+fn offline_needs_a_local_registry() {
+  let machine_dir = temp_dir("offline-registry");
+  let mut program = offline_program(&machine_dir, "https://example.com/registry.mec");
+  let (client, _) = crossbeam_channel::unbounded();
+  match program.download_dependencies(Some(client)) {
+    Err(err) => assert_eq!(err.id, 1239),
+    Ok(_) => panic!("loaded a remote registry offline"),
+  }
+}
+
+#[test]
+fn offline_lists_missing_machines() {
+  let machine_dir = temp_dir("offline-machines");
+  let source = temp_dir("offline-source");
+  std::fs::create_dir_all(&source).unwrap();
+  let registry = source.join("registry.mec");
+  std::fs::write(&registry, format!(r#"
+Registry
+=========
+
+  #mech/registry = [|name version url|
+    "math" "0.1.0" "https://example.com/math"
+    "io" "0.2.0" "{}/"]"#, source.display())).unwrap();
+  let mut program = offline_program(&machine_dir, registry.to_str().unwrap());
+  program.compile_program("#test = math/sin(angle: 0)".to_string()).unwrap();
+  let (client, _) = crossbeam_channel::unbounded();
+  match program.download_dependencies(Some(client)) {
+    Err(MechError{id: 1240, kind: MechErrorKind::GenericError(message), ..}) => {
+      assert!(message.contains("math v0.1.0 from https://example.com/math"));
+      assert!(!message.contains("io v0.2.0"));
+    }
+    result => panic!("{:?}", result),
+  }
+  assert_eq!(program.required_machines().into_iter().collect::<Vec<_>>(), vec!["math".to_string()]);
+}
+
+#[test]
+fn vendor_program_machines() {
+  let source = temp_dir("vendor-source");
+  std::fs::create_dir_all(&source).unwrap();
+  std::fs::write(source.join(machine_library("math")), b"not a library").unwrap();
+  let registry = source.join("registry.mec");
+  std::fs::write(&registry, format!(r#"
+Registry
+=========
+
+  #mech/registry = [|name version url|
+    "math" "0.1.0" "{0}/"
+    "io" "0.2.0" "{0}/"]"#, source.display())).unwrap();
+  let program_path = source.join("program.mec");
+  std::fs::write(&program_path, "#test = math/sin(angle: 0)").unwrap();
+  let dest = temp_dir("vendor-dest");
+  let vendored = vendor_machines(&program_path, &[registry.to_str().unwrap().to_string()], &dest, None).unwrap();
+  assert_eq!(vendored.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), vec!["math"]);
+  assert!(dest.join("registry.mec").is_file());
+  assert_eq!(std::fs::read(machine_path(&dest, "math", "0.1.0", &machine_library("math"))).unwrap(), b"not a library");
+  // The vendored directory is all an offline program needs
+  let mut program = offline_program(&dest, "https://example.com/registry.mec");
+  assert_eq!(program.lock_path(), Some(dest.join("mech.lock")));
+  program.load_registry(None).unwrap();
+  assert_eq!(program.required_machines().len(), 0);
+  program.compile_program("#test = math/sin(angle: 0)".to_string()).unwrap();
+  assert!(program.required_machines().iter().all(|name| machine_available(&dest, &program.machine_repository[name])));
+
+  // Vendoring again brings the registry up to date with the new machines
+  write_registry(&registry, &[("math", "0.2.0", &format!("{}/", source.display()))]);
+  let vendored = vendor_machines(&program_path, &[registry.to_str().unwrap().to_string()], &dest, None).unwrap();
+  assert_eq!(vendored.iter().map(|m| m.version.as_str()).collect::<Vec<_>>(), vec!["0.2.0"]);
+  let mut program = offline_program(&dest, "https://example.com/registry.mec");
+  program.load_registry(None).unwrap();
+  assert_eq!(program.machine_repository["math"].version, "0.2.0");
+  assert!(machine_available(&dest, &program.machine_repository["math"]));
+}
+
+#[test]
+fn vendor_uses_the_programs_lockfile() {
+  let source = temp_dir("vendor-lock-source");
+  std::fs::create_dir_all(&source).unwrap();
+  std::fs::write(source.join(machine_library("math")), b"not a library").unwrap();
+  let registry = source.join("registry.mec");
+  write_registry(&registry, &[("math", "0.1.0", &format!("{}/", source.display()))]);
+  let program = source.join("program.mec");
+  std::fs::write(&program, "#test = math/sin(angle: 0)").unwrap();
+  let mut lockfile = Lockfile::new();
+  lockfile.insert(RegistryEntry{name: "math".to_string(), version: "0.0.9".to_string(), url: format!("{}/", source.display()), sha256: None, description: None, mirrors: vec![]});
+  lockfile.write(&program_lock_path(&program)).unwrap();
+  assert_eq!(program_lock_path(&program), source.join("mech.lock"));
+  let dest = temp_dir("vendor-lock-dest");
+  let vendored = vendor_machines(&program, &[registry.to_str().unwrap().to_string()], &dest, None).unwrap();
+  assert_eq!(vendored.iter().map(|m| m.version.as_str()).collect::<Vec<_>>(), vec!["0.0.9"]);
+}
+
+fn write_registry(path: &std::path::Path, machines: &[(&str, &str, &str)]) {
+  let rows: Vec<String> = machines.iter().map(|(name, version, url)| format!("    \"{}\" \"{}\" \"{}\"", name, version, url)).collect();
+  std::fs::write(path, format!("\nRegistry\n=========\n\n  #mech/registry = [|name version url|\n{}]", rows.join("\n"))).unwrap();
+}
+
+#[test]
+fn lockfile_pins_versions() {
+  let dir = temp_dir("lock");
+  std::fs::create_dir_all(&dir).unwrap();
+  let registry = dir.join("registry.mec");
+  write_registry(&registry, &[("math", "0.1.0", "https://example.com/math"), ("io", "0.2.0", "https://example.com/io")]);
+  // Programs lock their machines in the machine directory unless told otherwise
+  let lock_path = dir.join("machines").join("mech.lock");
+  std::fs::create_dir_all(dir.join("machines")).unwrap();
+  assert_eq!(Lockfile::read(&lock_path).unwrap(), None);
+  let locked = RegistryEntry{name: "math".to_string(), version: "0.0.9".to_string(), url: "https://example.com/old".to_string(), sha256: Some("abc".to_string()), description: None, mirrors: vec![]};
+  let mut lockfile = Lockfile::new();
+  assert!(lockfile.insert(locked.clone()));
+  assert!(!lockfile.insert(locked.clone()));
+  lockfile.write(&lock_path).unwrap();
+  assert_eq!(Lockfile::read(&lock_path).unwrap(), Some(lockfile));
+
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let mut program = Program::new("test", 100, 1000, outgoing, incoming, registry.to_str().unwrap().to_string());
+  program.machine_dir = dir.join("machines");
+  assert_eq!(program.lock_path(), Some(lock_path.clone()));
+  program.load_registry(None).unwrap();
+  assert_eq!(program.machine_repository["math"], locked);
+  assert_eq!(program.machine_repository["io"].version, "0.2.0");
+
+  // Updating takes the registry's version and forgets the old digest
+  let updated = program.update_lock(None).unwrap();
+  assert_eq!(updated.iter().map(|m| (m.name.as_str(), m.version.as_str())).collect::<Vec<_>>(), vec![("math", "0.1.0")]);
+  let lockfile = Lockfile::read(&lock_path).unwrap().unwrap();
+  assert_eq!(lockfile.get("math").unwrap().sha256, None);
+  assert_eq!(lockfile.get("io"), None);
+  assert_eq!(program.machine_repository["math"].version, "0.1.0");
+  assert_eq!(program.update_lock(None).unwrap(), vec![]);
+}
+
+fn versions_program(dir: &std::path::Path, code: &str) -> Program {
+  std::fs::create_dir_all(dir).unwrap();
+  let registry = dir.join("registry.mec");
+  write_registry(&registry, &[
+    ("math", "0.1.0", "https://example.com/math"),
+    ("math", "0.2.0", "https://example.com/math"),
+    ("math", "0.1.5", "https://example.com/math"),
+    ("math", "v0.1.3", "https://example.com/math"),
+    ("io", "0.2.0", "https://example.com/io"),
+  ]);
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let mut program = Program::new("test", 100, 1000, outgoing, incoming, registry.to_str().unwrap().to_string());
+  program.machine_dir = dir.join("machines");
+  program.lock = LockLocation::File(dir.join("mech.lock"));
+  if !code.is_empty() {
+    program.compile_program(code.to_string()).unwrap();
+  }
+  program
+}
+
+#[test]
+fn highest_compatible_version() {
+  let mut program = versions_program(&temp_dir("versions-latest"), "");
+  program.load_registry(None).unwrap();
+  assert_eq!(program.registry_entries["math"].len(), 4);
+  assert_eq!(program.machine_repository["math"].version, "0.2.0");
+
+  let dir = temp_dir("versions-required");
+  let mut program = versions_program(&dir, r#"#mech/dependencies = [|name version| "math" ">= 0.1.3, < 0.2"]"#);
+  program.load_registry(None).unwrap();
+  assert_eq!(program.machine_repository["math"].version, "0.1.5");
+  assert_eq!(program.machine_repository["io"].version, "0.2.0");
+
+  // A locked version is kept while it meets the requirement
+  let mut lockfile = Lockfile::new();
+  lockfile.insert(program.registry_entries["math"][3].clone());
+  lockfile.write(&dir.join("mech.lock")).unwrap();
+  program.load_registry(None).unwrap();
+  assert_eq!(program.machine_repository["math"].version, "v0.1.3");
+  lockfile.insert(program.registry_entries["math"][1].clone());
+  lockfile.write(&dir.join("mech.lock")).unwrap();
+  program.load_registry(None).unwrap();
+  assert_eq!(program.machine_repository["math"].version, "0.1.5");
+}
+
+#[test]
+fn version_conflicts() {
+  let mut program = versions_program(&temp_dir("versions-conflict"), r#"#mech/dependencies = [|name version| "io" ">= 1.0"]"#);
+  match program.load_registry(None) {
+    Err(MechError{id: 1242, kind: MechErrorKind::GenericError(message), ..}) => {
+      assert_eq!(message, "No version of io meets >= 1.0. The registry lists 0.2.0.");
+    }
+    result => panic!("{:?}", result),
+  }
+  let mut program = versions_program(&temp_dir("versions-missing"), r#"#mech/dependencies = [|name version| "net" "0.1"]"#);
+  match program.load_registry(None) {
+    Err(MechError{id: 1242, kind: MechErrorKind::GenericError(message), ..}) => {
+      assert_eq!(message, "No version of net meets 0.1. The registry doesn't list it.");
+    }
+    result => panic!("{:?}", result),
+  }
+  let mut program = versions_program(&temp_dir("versions-invalid"), r#"#mech/dependencies = [|name version| "math" "newest"]"#);
+  assert_eq!(program.load_registry(None).unwrap_err().id, 1241);
+}
+
+#[test]
+fn registries_merge_in_order() {
+  let dir = temp_dir("registries");
+  std::fs::create_dir_all(&dir).unwrap();
+  let (public, mirror) = (dir.join("public.mec"), dir.join("mirror.mec"));
+  write_registry(&mirror, &[("math", "0.1.0", "https://mirror.example.com/math")]);
+  write_registry(&public, &[("math", "0.1.0", "https://example.com/math"), ("math", "0.2.0", "https://example.com/math"), ("io", "0.2.0", "https://example.com/io")]);
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let mut program = Program::new("test", 100, 1000, outgoing, incoming, format!("file://{}", dir.join("missing.mec").display()));
+  program.registries.push(format!("file://{}", mirror.display()));
+  program.registries.push(public.to_str().unwrap().to_string());
+  program.machine_dir = dir.join("machines");
+  program.lock = LockLocation::Off;
+  program.load_registry(None).unwrap();
+  let math = &program.registry_entries["math"];
+  assert_eq!(math.len(), 2);
+  assert_eq!(math[0].url, "https://mirror.example.com/math");
+  assert_eq!(math[0].mirrors, vec!["https://example.com/math".to_string()]);
+  assert_eq!(math[1].url, "https://example.com/math");
+  assert_eq!(program.machine_repository["math"].version, "0.2.0");
+  assert_eq!(program.machine_repository["io"].version, "0.2.0");
+
+  // Only when every registry fails is it an error
+  program.registries = vec![dir.join("missing.mec").to_str().unwrap().to_string(), "file:///nowhere/registry.mec".to_string()];
+  program.registry_entries.clear();
+  assert_eq!(program.load_registry(None).unwrap_err().id, 1243);
+}
+
+#[test]
+fn download_falls_back_to_mirrors() {
+  let source = temp_dir("mirror-source");
+  std::fs::create_dir_all(&source).unwrap();
+  std::fs::write(source.join("libmech_test.so"), b"not a library").unwrap();
+  let machine_dir = temp_dir("mirror-cache");
+  let entry = RegistryEntry{name: "test".to_string(), version: "0.0.1".to_string(), url: "/nowhere/".to_string(), sha256: None, description: None, mirrors: vec![format!("file://{}/", source.display())]};
+  assert!(machine_available(&machine_dir, &entry));
+  let cached = cache_machine(&machine_dir, "libmech_test.so", &entry, false, None).unwrap();
+  assert_eq!(std::fs::read(cached).unwrap(), b"not a library");
+}
+
+#[test]
+fn offline_download_skips_remote_urls() {
+  let source = temp_dir("offline-mirror-source");
+  std::fs::create_dir_all(&source).unwrap();
+  std::fs::write(source.join("libmech_test.so"), b"not a library").unwrap();
+  let machine_dir = temp_dir("offline-mirror-cache");
+  // Nothing listens at the primary URL, and offline we never try it
+  let entry = RegistryEntry{name: "test".to_string(), version: "0.0.1".to_string(), url: "http://127.0.0.1:1/test".to_string(), sha256: None, description: None, mirrors: vec![format!("file://{}/", source.display())]};
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let cached = cache_machine(&machine_dir, "libmech_test.so", &entry, true, Some(outgoing)).unwrap();
+  assert_eq!(std::fs::read(cached).unwrap(), b"not a library");
+  let messages: Vec<String> = incoming.try_iter().filter_map(|message| match message {ClientMessage::String(message) => Some(message), _ => None}).collect();
+  assert!(messages.iter().all(|message| !message.contains("Downloading") && !message.contains("Failed")));
+
+  // With only remote URLs there's nothing to try
+  let entry = RegistryEntry{mirrors: vec![], ..entry};
+  let machine_dir = temp_dir("offline-remote-cache");
+  assert_eq!(cache_machine(&machine_dir, "libmech_test.so", &entry, true, None).unwrap_err().id, 1281);
+}
+
+#[test]
+fn registry_ttl() {
+  let dir = temp_dir("ttl");
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  // Nothing listens here, so any download fails
+  let mut program = Program::new("test", 100, 1000, outgoing, incoming, "http://127.0.0.1:1/registry.mec".to_string());
+  program.machine_dir = dir.clone();
+  program.lock = LockLocation::Off;
+  std::fs::create_dir_all(&dir).unwrap();
+  write_registry(&program.registry_cache_path(0), &[("math", "0.1.0", "https://example.com/math")]);
+  assert!(registry_age(&program.registry_cache_path(0)).unwrap() < DEFAULT_REGISTRY_TTL);
+
+  // A fresh copy is used without going to the network
+  let (client, messages) = crossbeam_channel::unbounded();
+  program.load_registry(Some(client)).unwrap();
+  assert_eq!(program.machine_repository["math"].version, "0.1.0");
+  let messages: Vec<String> = messages.try_iter().filter_map(|m| match m { ClientMessage::String(s) => Some(s), _ => None }).collect();
+  assert!(messages.iter().all(|m| !m.contains("Downloading")));
+
+  // A stale one is fetched again, and still used when that fails
+  program.registry_ttl = Some(std::time::Duration::from_secs(0));
+  let (client, messages) = crossbeam_channel::unbounded();
+  assert_eq!(program.refresh_registry(Some(client)).unwrap(), vec![]);
+  let messages: Vec<String> = messages.try_iter().filter_map(|m| match m { ClientMessage::String(s) => Some(s), _ => None }).collect();
+  assert!(messages.iter().any(|m| m.contains("Downloading")));
+  assert!(messages.iter().any(|m| m.contains("using the cached one")));
+  assert_eq!(program.machine_repository["math"].version, "0.1.0");
+}
+
+#[test]
+fn refresh_reports_version_changes() {
+  let dir = temp_dir("refresh");
+  std::fs::create_dir_all(&dir).unwrap();
+  let registry = dir.join("registry.mec");
+  write_registry(&registry, &[("math", "0.1.0", "https://example.com/math"), ("io", "0.2.0", "https://example.com/io")]);
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let mut program = Program::new("test", 100, 1000, outgoing, incoming, registry.to_str().unwrap().to_string());
+  program.machine_dir = dir.join("machines");
+  program.lock = LockLocation::Off;
+  program.load_registry(None).unwrap();
+  write_registry(&registry, &[("math", "0.1.0", "https://example.com/math"), ("math", "0.2.0", "https://example.com/math"), ("net", "0.1.0", "https://example.com/net")]);
+  let change = |name: &str, old: Option<&str>, new: Option<&str>| VersionChange{name: name.to_string(), old: old.map(|v| v.to_string()), new: new.map(|v| v.to_string())};
+  assert_eq!(program.refresh_registry(None).unwrap(), vec![
+    change("io", Some("0.2.0"), None),
+    change("math", Some("0.1.0"), Some("0.2.0")),
+    change("net", None, Some("0.1.0")),
+  ]);
+  assert_eq!(program.refresh_registry(None).unwrap(), vec![]);
+}
+
+#[test]
+fn search_machines() {
+  let dir = temp_dir("search");
+  std::fs::create_dir_all(&dir).unwrap();
+  let registry = dir.join("registry.mec");
+  std::fs::write(&registry, r#"
+Registry
+=========
+
+  #mech/registry = [|name version url description|
+    "math" "0.1.10" "https://example.com/math" "Trigonometry and rounding"
+    "math" "0.1.9" "https://example.com/math" ""
+    "io" "0.2.0" "https://example.com/io" "Print to STDOUT"
+    "matrix" "0.1.0" "https://example.com/matrix" "Linear algebra"]"#).unwrap();
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  let mut program = Program::new("test", 100, 1000, outgoing, incoming, registry.to_str().unwrap().to_string());
+  program.machine_dir = dir.join("machines");
+  let found = |program: &mut Program, query: &str| program.search_machines(query, None).unwrap().into_iter().map(|m| format!("{} {}", m.name, m.version)).collect::<Vec<_>>();
+  assert_eq!(found(&mut program, ""), vec!["io 0.2.0", "math 0.1.9", "math 0.1.10", "matrix 0.1.0"]);
+  assert_eq!(found(&mut program, "MAT"), vec!["math 0.1.9", "math 0.1.10", "matrix 0.1.0"]);
+  assert_eq!(found(&mut program, "algebra"), vec!["matrix 0.1.0"]);
+  assert_eq!(found(&mut program, "net"), Vec::<String>::new());
+  let io = program.search_machines("io", None).unwrap();
+  assert_eq!(io[0].url, "https://example.com/io");
+  assert_eq!(io[0].description, Some("Print to STDOUT".to_string()));
+  assert_eq!(program.search_machines("math", None).unwrap()[0].description, None);
+}
@@ -0,0 +1,91 @@
+extern crate mech_program;
+extern crate mech_core;
+use mech_program::*;
+use mech_core::*;
+
+fn foo_log() -> Vec<Transaction> {
+  let foo_id = hash_str("foo");
+  vec![
+    vec![
+      Change::NewTable{table_id: foo_id, rows: 1, columns: 1},
+      Change::ColumnKind{table_id: foo_id, column_ix: 0, column_kind: ValueKind::F32},
+      Change::ColumnAlias{table_id: foo_id, column_ix: 0, column_alias: hash_str("x")},
+    ],
+    vec![Change::Set((foo_id, vec![(TableIndex::Index(1), TableIndex::Alias(hash_str("x")), Value::F32(F32::new(1.5)))]))],
+  ]
+}
+
+#[test]
+fn migration_renames_and_converts() {
+  let migration = Migration::new()
+    .rename_table("foo", "bar")
+    .rename_column("bar", "x", "y")
+    .convert_column("bar", "y", ValueKind::F64);
+  let mut core = Core::new();
+  let mut replay = migration.replay();
+  for txn in foo_log() {
+    let txn = replay.migrate(&txn, &core).unwrap();
+    core.process_transaction(&txn).unwrap();
+  }
+  let table = core.get_table_by_id(hash_str("bar")).unwrap();
+  let table = table.borrow();
+  assert_eq!(table.col_map.get_index(&hash_str("y")).unwrap(), 0);
+  assert_eq!(table.get_raw(0, 0).unwrap(), Value::F64(F64::new(1.5)));
+  assert!(core.get_table_by_id(hash_str("foo")).is_err());
+}
+
+#[test]
+fn migration_mismatches_are_errors() {
+  let core = Core::new();
+  let foo_id = hash_str("foo");
+
+  // A column that isn't in the table
+  let migration = Migration::new().rename_column("foo", "x", "y");
+  let mut replay = migration.replay();
+  replay.migrate(&foo_log()[0], &core).unwrap();
+  let set_x = vec![Change::Set((foo_id, vec![(TableIndex::Index(1), TableIndex::Alias(hash_str("z")), Value::F32(F32::new(1.5)))]))];
+  assert_eq!(replay.migrate(&set_x, &core).unwrap_err().id, 1312);
+
+  // A value of the wrong kind
+  let migration = Migration::new();
+  let mut replay = migration.replay();
+  replay.migrate(&foo_log()[0], &core).unwrap();
+  let set_bool = vec![Change::Set((foo_id, vec![(TableIndex::Index(1), TableIndex::Index(1), Value::Bool(true))]))];
+  assert_eq!(replay.migrate(&set_bool, &core).unwrap_err().id, 1313);
+
+  // A conversion that would lose data
+  let migration = Migration::new().convert_column("foo", "x", ValueKind::U8);
+  let mut replay = migration.replay();
+  replay.migrate(&foo_log()[0], &core).unwrap();
+  assert_eq!(replay.migrate(&foo_log()[1], &core).unwrap_err().id, 1314);
+}
+
+#[test]
+fn migration_converts_integers_exactly() {
+  let stamps = hash_str("stamps");
+  let big = (1i64 << 53) + 1;
+  let log = [
+    vec![
+      Change::NewTable{table_id: stamps, rows: 1, columns: 1},
+      Change::ColumnKind{table_id: stamps, column_ix: 0, column_kind: ValueKind::I64},
+      Change::ColumnAlias{table_id: stamps, column_ix: 0, column_alias: hash_str("t")},
+    ],
+    vec![Change::Set((stamps, vec![(TableIndex::Index(1), TableIndex::Index(1), Value::I64(I64::new(big)))]))],
+  ];
+  let converted = |kind: ValueKind| {
+    let core = Core::new();
+    let migration = Migration::new().convert_column("stamps", "t", kind);
+    let mut replay = migration.replay();
+    replay.migrate(&log[0], &core).unwrap();
+    replay.migrate(&log[1], &core).map(|txn| match &txn[0] {
+      Change::Set((_, values)) => values[0].2.clone(),
+      _ => unreachable!(),
+    }).map_err(|err| err.id)
+  };
+  assert_eq!(converted(ValueKind::I128).unwrap(), Value::I128(I128::new(big as i128)));
+  assert_eq!(converted(ValueKind::U64).unwrap(), Value::U64(U64::new(big as u64)));
+  // Floats and narrower integers can't hold it
+  assert_eq!(converted(ValueKind::F64).unwrap_err(), 1314);
+  assert_eq!(converted(ValueKind::F32).unwrap_err(), 1314);
+  assert_eq!(converted(ValueKind::I32).unwrap_err(), 1314);
+}
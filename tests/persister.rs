@@ -0,0 +1,412 @@
+extern crate mech_program;
+extern crate mech_core;
+extern crate mech_utilities;
+extern crate bincode;
+extern crate crc32fast;
+use mech_program::*;
+use mech_core::*;
+use mech_utilities::*;
+use std::io::Write;
+
+// A fresh path in the temp directory, with any segments, snapshot or
+// manifest from an earlier run removed.
+fn db_path(name: &str) -> String {
+  let file_name = format!("mech-{}.mdb", name);
+  for entry in std::fs::read_dir(std::env::temp_dir()).unwrap() {
+    let entry = entry.unwrap();
+    if entry.file_name().to_str().unwrap().starts_with(&file_name) {
+      std::fs::remove_file(entry.path()).ok();
+    }
+  }
+  std::env::temp_dir().join(file_name).to_str().unwrap().to_string()
+}
+
+fn new_table(name: &str) -> Change {
+  Change::NewTable{table_id: hash_str(name), rows: 1, columns: 1}
+}
+
+fn set_value(name: &str, value: Value) -> Change {
+  Change::Set((hash_str(name), vec![(TableIndex::Index(1), TableIndex::Index(1), value)]))
+}
+
+#[test]
+fn persister_round_trip() {
+  let path = db_path("round-trip");
+  let persister = Persister::new(FileBackend::new(&path));
+  persister.send(vec![new_table("foo"), set_value("foo", Value::Bool(true))]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.close();
+  persister.wait();
+
+  let (transactions, end) = Persister::load(&FileBackend::new(&path)).unwrap();
+  assert_eq!(end, LogEnd::Clean);
+  assert_eq!(transactions.iter().map(|txn| txn.len()).collect::<Vec<usize>>(), vec![2, 1]);
+}
+
+#[test]
+fn persister_torn_tail() {
+  let path = db_path("torn-tail");
+  let persister = Persister::new(FileBackend::new(&path));
+  persister.send(vec![new_table("foo")]).unwrap();
+  persister.close();
+  persister.wait();
+  let intact_len = std::fs::metadata(&path).unwrap().len();
+  let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+  file.write_all(&[42, 0, 0]).unwrap();
+
+  let (transactions, end) = Persister::load(&FileBackend::new(&path)).unwrap();
+  assert_eq!(end, LogEnd::TornTail(LogPosition{segment: 0, offset: intact_len}));
+  assert_eq!(transactions.len(), 1);
+}
+
+#[test]
+fn persister_bogus_record_length() {
+  let path = db_path("bogus-length");
+  let persister = Persister::new(FileBackend::new(&path));
+  persister.send(vec![new_table("foo")]).unwrap();
+  persister.close();
+  persister.wait();
+  let intact_len = std::fs::metadata(&path).unwrap().len();
+  // A length near 4 GiB in front of a few bytes is never allocated up front
+  let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+  file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+  file.write_all(&[0, 0, 0, 0, 1, 2, 3]).unwrap();
+
+  let (transactions, end) = Persister::load(&FileBackend::new(&path)).unwrap();
+  assert_eq!(end, LogEnd::TornTail(LogPosition{segment: 0, offset: intact_len}));
+  assert_eq!(transactions.len(), 1);
+}
+
+#[test]
+fn persister_rejects_foreign_files() {
+  let path = db_path("foreign");
+  std::fs::write(&path, b"not a database").unwrap();
+  assert!(Persister::load(&FileBackend::new(&path)).is_err());
+}
+
+#[test]
+fn persister_upgrades_version_1_logs() {
+  let path = db_path("version-1");
+  let mut bytes = b"MECHDB".to_vec();
+  bytes.extend_from_slice(&1u16.to_le_bytes());
+  for change in [new_table("foo"), set_value("foo", Value::Bool(true))] {
+    let payload = bincode::serialize(&change).unwrap();
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+  }
+  std::fs::write(&path, bytes).unwrap();
+
+  let persister = Persister::new(FileBackend::new(&path));
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.close();
+  persister.wait();
+
+  let (transactions, end) = Persister::load(&FileBackend::new(&path)).unwrap();
+  assert_eq!(end, LogEnd::Clean);
+  assert_eq!(transactions.iter().map(|txn| txn.len()).collect::<Vec<usize>>(), vec![1, 1, 1]);
+}
+
+#[test]
+fn persister_keeps_corrupt_old_logs() {
+  let path = db_path("version-1-corrupt");
+  let mut bytes = b"MECHDB".to_vec();
+  bytes.extend_from_slice(&1u16.to_le_bytes());
+  for (ix, change) in [new_table("foo"), set_value("foo", Value::Bool(true)), set_value("foo", Value::Bool(false))].iter().enumerate() {
+    let payload = bincode::serialize(change).unwrap();
+    let crc = match ix {
+      1 => 0,
+      _ => crc32fast::hash(&payload),
+    };
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+  }
+  std::fs::write(&path, &bytes).unwrap();
+
+  // The write is refused, and the records after the bad one are still there
+  let persister = Persister::new(FileBackend::new(&path));
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  assert_eq!(persister.flush().unwrap_err().id, 1318);
+  assert_eq!(persister.errors().iter().map(|err| err.id).collect::<Vec<u64>>(), vec![1316]);
+  persister.close();
+  persister.wait();
+  assert_eq!(std::fs::read(&path).unwrap(), bytes);
+}
+
+fn foo_table(value: bool) -> MiniTable {
+  MiniTable {
+    id: hash_str("foo"),
+    dynamic: false,
+    rows: 1,
+    cols: 1,
+    col_kinds: vec![ValueKind::Bool],
+    col_map: (1, vec![], vec![]),
+    row_map: (1, vec![], vec![]),
+    data: vec![vec![Value::Bool(value)]],
+    dictionary: vec![],
+  }
+}
+
+#[test]
+fn persister_compaction() {
+  let path = db_path("compaction");
+  let persister = Persister::new(FileBackend::new(&path));
+  persister.send(vec![new_table("foo")]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(true))]).unwrap();
+  persister.snapshot(vec![foo_table(true)]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.close();
+  persister.wait();
+
+  let (transactions, end) = Persister::load(&FileBackend::new(&path)).unwrap();
+  assert_eq!(end, LogEnd::Clean);
+  // NewTable, ColumnKind and Set from the snapshot, then the write after it
+  assert_eq!(transactions.iter().map(|txn| txn.len()).collect::<Vec<usize>>(), vec![3, 1]);
+}
+
+#[test]
+fn persister_flush_and_errors() {
+  let path = db_path("flush");
+  let persister = Persister::with_fsync_policy(FileBackend::new(&path), FsyncPolicy::Interval(10));
+  persister.send(vec![new_table("foo")]).unwrap();
+  persister.flush().unwrap();
+  let (transactions, _) = Persister::load(&FileBackend::new(&path)).unwrap();
+  assert_eq!(transactions.len(), 1);
+  assert!(persister.errors().is_empty());
+  persister.close();
+  persister.wait();
+
+  // A log we can't open is reported instead of killing the persister
+  let dir = std::env::temp_dir().join("mech-no-such-dir").join("flush.mdb");
+  let persister = Persister::new(FileBackend::new(dir.to_str().unwrap()));
+  // and a flush after a failed write fails too, since that write isn't on disk
+  persister.send(vec![new_table("foo")]).unwrap();
+  assert_eq!(persister.flush().unwrap_err().id, 1318);
+  assert_eq!(persister.errors().len(), 1);
+  persister.send(vec![new_table("foo")]).unwrap();
+  assert_eq!(persister.flush().unwrap_err().id, 1318);
+  assert_eq!(persister.errors().len(), 1);
+  // Nothing has failed since the last flush
+  persister.flush().unwrap();
+  persister.close();
+  persister.wait();
+}
+
+#[test]
+fn persister_segment_rotation() {
+  let path = db_path("rotation");
+  let options = PersisterOptions{max_segment_size: Some(1), keep_redundant_segments: true, ..PersisterOptions::default()};
+  let persister = Persister::with_options(FileBackend::new(&path), options);
+  persister.send(vec![new_table("foo")]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(true))]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.flush().unwrap();
+  assert!(std::path::Path::new(&format!("{}.000002", path)).exists());
+
+  let (transactions, end) = Persister::load(&FileBackend::new(&path)).unwrap();
+  assert_eq!(end, LogEnd::Clean);
+  assert_eq!(transactions.len(), 3);
+
+  // The snapshot makes the first three segments redundant, but they stay
+  // until the log is pruned.
+  persister.snapshot(vec![foo_table(false)]).unwrap();
+  persister.flush().unwrap();
+  assert!(std::path::Path::new(&path).exists());
+  assert_eq!(Persister::load(&FileBackend::new(&path)).unwrap().0.len(), 1);
+  persister.prune().unwrap();
+  persister.flush().unwrap();
+  assert!(!std::path::Path::new(&path).exists());
+  assert!(!std::path::Path::new(&format!("{}.000002", path)).exists());
+  assert_eq!(Persister::load(&FileBackend::new(&path)).unwrap().0.len(), 1);
+  persister.close();
+  persister.wait();
+}
+
+#[test]
+fn persister_memory_backend() {
+  let backend = MemoryBackend::new();
+  let options = PersisterOptions{max_segment_size: Some(1), ..PersisterOptions::default()};
+  let persister = Persister::with_options(backend.clone(), options);
+  persister.send(vec![new_table("foo")]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(true))]).unwrap();
+  persister.flush().unwrap();
+  assert_eq!(backend.streams(), vec!["000001", "log", "manifest"]);
+
+  persister.snapshot(vec![foo_table(true)]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.close();
+  persister.wait();
+  assert_eq!(backend.streams(), vec!["000002", "manifest", "snapshot"]);
+
+  // A torn record at the end of the log is dropped on load
+  let mut torn = backend.clone();
+  torn.append("000002", &[42, 0, 0]).unwrap();
+  let (transactions, end) = Persister::load(&backend).unwrap();
+  assert_eq!(end, LogEnd::TornTail(LogPosition{segment: 2, offset: backend.size("000002") - 3}));
+  assert_eq!(transactions.iter().map(|txn| txn.len()).collect::<Vec<usize>>(), vec![3, 1]);
+}
+
+#[test]
+fn persister_policy() {
+  let backend = MemoryBackend::new();
+  let policy = PersistencePolicy::new().exclude("#time/*").exclude("io/sensor");
+  let persister = Persister::with_options(backend.clone(), PersisterOptions{policy, ..PersisterOptions::default()});
+  persister.name_tables(vec!["time/timer".to_string()]).unwrap();
+  persister.send(vec![new_table("foo"), new_table("time/timer"), new_table("io/sensor")]).unwrap();
+  persister.send(vec![set_value("time/timer", Value::Bool(true))]).unwrap();
+  persister.close();
+  persister.wait();
+  // Only foo is written, and the second transaction is dropped entirely
+  let (transactions, _) = Persister::load(&backend).unwrap();
+  assert_eq!(transactions.len(), 1);
+  assert_eq!(transactions[0].len(), 1);
+  assert_eq!(persister::change_table_id(&transactions[0][0]), hash_str("foo"));
+
+  let policy = PersistencePolicy::new().include("foo");
+  assert!(policy.persists(hash_str("foo")));
+  assert!(!policy.persists(hash_str("bar")));
+}
+
+#[test]
+fn persister_log_reader_and_export() {
+  let backend = MemoryBackend::new();
+  let persister = Persister::new(backend.clone());
+  persister.send(vec![new_table("foo"), set_value("foo", Value::Bool(true))]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.close();
+  persister.wait();
+
+  let changes: Vec<(LogPosition,Change)> = LogReader::new(&backend).unwrap().map(|item| item.unwrap()).collect();
+  let offsets: Vec<u64> = changes.iter().map(|(position,_)| position.offset).collect();
+  assert_eq!(offsets[0], 9);
+  assert_eq!(offsets[0], offsets[1]);
+  assert!(offsets[2] > offsets[1]);
+
+  let mut names = std::collections::HashMap::new();
+  names.insert(hash_str("foo"), "foo".to_string());
+  let mut json = vec![];
+  let end = persister::export_json_lines(&mut LogReader::new(&backend).unwrap(), &names, &mut json).unwrap();
+  assert_eq!(end, LogEnd::Clean);
+  let json = String::from_utf8(json).unwrap();
+  assert_eq!(json.lines().count(), 3);
+  assert!(json.lines().all(|line| line.contains("\"table\":\"foo\"")));
+
+  let mut csv = vec![];
+  persister::export_csv(&mut LogReader::new(&backend).unwrap(), &names, &mut csv).unwrap();
+  let csv = String::from_utf8(csv).unwrap();
+  let lines: Vec<&str> = csv.lines().collect();
+  assert_eq!(lines[0], "segment,offset,table,change,row,column,value");
+  assert_eq!(lines[1], "0,9,foo,new_table,1,1,");
+  assert_eq!(lines[2], "0,9,foo,set,1,1,true");
+  assert_eq!(lines.len(), 4);
+}
+
+// Refuses to read a segment whole, so a log reader has to stream it
+struct StreamingBackend(MemoryBackend);
+
+impl PersistBackend for StreamingBackend {
+  fn append(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError> { self.0.append(stream, bytes) }
+  fn flush(&mut self, stream: &str) -> Result<(),MechError> { self.0.flush(stream) }
+  fn read_all(&self, stream: &str) -> Result<Option<Vec<u8>>,MechError> {
+    match stream {
+      persister::MANIFEST_STREAM | persister::SNAPSHOT_STREAM => self.0.read_all(stream),
+      _ => panic!("read all of {}", stream),
+    }
+  }
+  fn open_read(&self, stream: &str) -> Result<Option<Box<dyn std::io::Read>>,MechError> { self.0.open_read(stream) }
+  fn truncate(&mut self, stream: &str, len: u64) -> Result<(),MechError> { self.0.truncate(stream, len) }
+  fn replace(&mut self, stream: &str, bytes: &[u8]) -> Result<(),MechError> { self.0.replace(stream, bytes) }
+  fn remove(&mut self, stream: &str) -> Result<(),MechError> { self.0.remove(stream) }
+  fn size(&self, stream: &str) -> u64 { self.0.size(stream) }
+}
+
+#[test]
+fn persister_log_reader_streams() {
+  let backend = MemoryBackend::new();
+  let persister = Persister::new(backend.clone());
+  let kind = Change::ColumnKind{table_id: hash_str("foo"), column_ix: 0, column_kind: ValueKind::Bool};
+  persister.send(vec![new_table("foo"), kind, set_value("foo", Value::Bool(true))]).unwrap();
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.close();
+  persister.wait();
+
+  let backend = StreamingBackend(backend);
+  let mut reader = LogReader::new(&backend).unwrap();
+  assert_eq!(reader.by_ref().collect::<Result<Vec<_>,_>>().unwrap().len(), 4);
+  assert_eq!(reader.end(), LogEnd::Clean);
+  let foo = Persister::table_at(&backend, hash_str("foo"), PointInTime::Timestamp(u64::MAX)).unwrap();
+  assert_eq!(foo.data, vec![vec![Value::Bool(false)]]);
+
+  // Files are read a piece at a time too
+  let path = db_path("streaming");
+  let persister = Persister::new(FileBackend::new(&path));
+  persister.send(vec![new_table("foo")]).unwrap();
+  persister.close();
+  persister.wait();
+  let backend = FileBackend::new(&path);
+  assert_eq!(LogReader::new(&backend).unwrap().count(), 1);
+}
+
+#[test]
+fn persister_point_in_time() {
+  let backend = MemoryBackend::new();
+  let persister = Persister::new(backend.clone());
+  let kind = Change::ColumnKind{table_id: hash_str("foo"), column_ix: 0, column_kind: ValueKind::Bool};
+  persister.send(vec![new_table("foo"), kind, set_value("foo", Value::Bool(true))]).unwrap();
+  persister.flush().unwrap();
+  std::thread::sleep(std::time::Duration::from_millis(5));
+  let between = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+  std::thread::sleep(std::time::Duration::from_millis(5));
+  persister.send(vec![set_value("foo", Value::Bool(false))]).unwrap();
+  persister.close();
+  persister.wait();
+
+  let positions: Vec<LogPosition> = LogReader::new(&backend).unwrap().map(|item| item.unwrap().0).collect();
+  let first = Persister::table_at(&backend, hash_str("foo"), PointInTime::Position(positions[0])).unwrap();
+  assert_eq!(first.data, vec![vec![Value::Bool(true)]]);
+  let last = Persister::table_at(&backend, hash_str("foo"), PointInTime::Position(positions[3])).unwrap();
+  assert_eq!(last.data, vec![vec![Value::Bool(false)]]);
+  let then = Persister::table_at(&backend, hash_str("foo"), PointInTime::Timestamp(between)).unwrap();
+  assert_eq!(then.data, vec![vec![Value::Bool(true)]]);
+  assert!(Persister::table_at(&backend, hash_str("foo"), PointInTime::Timestamp(0)).is_err());
+  assert!(Persister::table_at(&backend, hash_str("bar"), PointInTime::Timestamp(between)).is_err());
+}
+
+#[test]
+fn persister_compression() {
+  let big_set = || {
+    let values = (1..=100).map(|row| (TableIndex::Index(row), TableIndex::Index(1), Value::Bool(true))).collect();
+    Change::Set((hash_str("foo"), values))
+  };
+  let plain = MemoryBackend::new();
+  let persister = Persister::new(plain.clone());
+  persister.send(vec![new_table("foo"), big_set()]).unwrap();
+  persister.close();
+  persister.wait();
+
+  let compressed = MemoryBackend::new();
+  let options = PersisterOptions{codec: Codec::Deflate, ..PersisterOptions::default()};
+  let persister = Persister::with_options(compressed.clone(), options);
+  persister.send(vec![new_table("foo"), big_set()]).unwrap();
+  persister.close();
+  persister.wait();
+  assert_eq!(compressed.read_all("log").unwrap().unwrap()[8], 1);
+  assert!(compressed.size("log") < plain.size("log") / 4);
+  let (transactions, end) = Persister::load(&compressed).unwrap();
+  assert_eq!(end, LogEnd::Clean);
+  assert_eq!(transactions[0].len(), 2);
+
+  // An uncompressed log keeps loading once compression is turned on. New
+  // records go into the existing segment uncompressed until it rotates.
+  let options = PersisterOptions{codec: Codec::Deflate, max_segment_size: Some(1), keep_redundant_segments: true, ..PersisterOptions::default()};
+  let persister = Persister::with_options(plain.clone(), options);
+  persister.send(vec![big_set()]).unwrap();
+  persister.close();
+  persister.wait();
+  assert_eq!(plain.read_all("log").unwrap().unwrap()[8], 0);
+  assert_eq!(plain.read_all("000001").unwrap().unwrap()[8], 1);
+  let (transactions, end) = Persister::load(&plain).unwrap();
+  assert_eq!(end, LogEnd::Clean);
+  assert_eq!(transactions.iter().map(|txn| txn.len()).collect::<Vec<usize>>(), vec![2, 1]);
+}
@@ -0,0 +1,370 @@
+extern crate mech_program;
+extern crate mech_core;
+extern crate mech_utilities;
+use mech_program::*;
+use mech_core::*;
+use mech_utilities::*;
+use std::path::{Path, PathBuf};
+
+fn temp_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("mech-runloop-{}-{}", name, std::process::id()));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+// A runner that persists to <dir>/test.mdb and reads its registry from a
+// local file, so it never reaches for the network
+fn runner(dir: &Path) -> ProgramRunner {
+  let registry = dir.join("registry.mec");
+  std::fs::write(&registry, "\nRegistry\n=========\n\n  #mech/registry = [|name version url|\n    \"math\" \"0.1.0\" \"https://example.com/math\"]").unwrap();
+  let mut runner = ProgramRunner::new("test");
+  runner.registries = vec![registry.to_str().unwrap().to_string()];
+  runner.machine_dir = dir.join("machines");
+  runner.lock = LockLocation::Off;
+  runner.persist(dir.join("test").to_str().unwrap());
+  runner
+}
+
+// Receives messages until one matches, and returns it
+fn wait_for(running: &RunLoop, matches: fn(&ClientMessage) -> bool) -> ClientMessage {
+  loop {
+    let message = running.receive().unwrap();
+    if matches(&message) {
+      return message;
+    }
+  }
+}
+
+fn stop(running: RunLoop) {
+  running.send(RunLoopMessage::Stop).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Stop));
+  running.wait();
+}
+
+fn set_foo(value: f32) -> Transaction {
+  vec![Change::Set((hash_str("foo"), vec![(TableIndex::Index(1), TableIndex::Index(1), Value::F32(F32::new(value)))]))]
+}
+
+fn new_foo() -> Transaction {
+  vec![
+    Change::NewTable{table_id: hash_str("foo"), rows: 1, columns: 1},
+    Change::ColumnKind{table_id: hash_str("foo"), column_ix: 0, column_kind: ValueKind::F32},
+  ]
+}
+
+fn foo(running: &RunLoop) -> ClientMessage {
+  running.send(RunLoopMessage::GetValue((hash_str("foo"), TableIndex::Index(1), TableIndex::Index(1)))).unwrap();
+  wait_for(running, |m| matches!(m, ClientMessage::Value(_) | ClientMessage::Error(_)))
+}
+
+fn is_foo(message: ClientMessage, value: f32) -> bool {
+  match message {
+    ClientMessage::Value(v) => v == Value::F32(F32::new(value)),
+    _ => false,
+  }
+}
+
+#[test]
+fn compact_through_the_run_loop() {
+  let dir = temp_dir("compact");
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  for txn in [new_foo(), set_foo(1.0), set_foo(2.0)] {
+    running.send(RunLoopMessage::Transaction(txn)).unwrap();
+    wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  }
+  running.compact().unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::String(_) | ClientMessage::Error(_))) {
+    ClientMessage::String(message) => assert_eq!(message, "Compacted 1 tables"),
+    message => panic!("{:?}", message),
+  }
+  stop(running);
+  assert!(Path::new(&persister::snapshot_path(dir.join("test.mdb").to_str().unwrap())).is_file());
+
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  assert!(is_foo(foo(&running), 2.0));
+  stop(running);
+}
+
+#[test]
+fn refresh_registry_through_the_run_loop() {
+  let dir = temp_dir("refresh");
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  std::fs::write(dir.join("registry.mec"), "\nRegistry\n=========\n\n  #mech/registry = [|name version url|\n    \"math\" \"0.2.0\" \"https://example.com/math\"]").unwrap();
+  running.refresh_registry().unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::RegistryRefreshed(_) | ClientMessage::Error(_))) {
+    ClientMessage::RegistryRefreshed(changes) => assert_eq!(changes, vec![VersionChange{name: "math".to_string(), old: Some("0.1.0".to_string()), new: Some("0.2.0".to_string())}]),
+    message => panic!("{:?}", message),
+  }
+  stop(running);
+}
+
+#[test]
+fn list_machines_through_the_run_loop() {
+  let dir = temp_dir("list");
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  running.list_machines("MA").unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::Machines(_) | ClientMessage::Error(_))) {
+    ClientMessage::Machines(machines) => {
+      assert_eq!(machines.iter().map(|m| (m.name.as_str(), m.version.as_str())).collect::<Vec<_>>(), vec![("math", "0.1.0")]);
+    }
+    message => panic!("{:?}", message),
+  }
+  running.list_machines("io").unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::Machines(_) | ClientMessage::Error(_))) {
+    ClientMessage::Machines(machines) => assert!(machines.is_empty()),
+    message => panic!("{:?}", message),
+  }
+  stop(running);
+}
+
+#[test]
+fn state_survives_a_restart() {
+  let dir = temp_dir("restart");
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  for txn in [new_foo(), set_foo(1.0), set_foo(2.0)] {
+    running.send(RunLoopMessage::Transaction(txn)).unwrap();
+    wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  }
+  assert!(is_foo(foo(&running), 2.0));
+  stop(running);
+
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  assert!(is_foo(foo(&running), 2.0));
+  running.send(RunLoopMessage::Transaction(set_foo(3.0))).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  stop(running);
+
+  // The second run's transactions are appended to the same log
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  assert!(is_foo(foo(&running), 3.0));
+  stop(running);
+}
+
+#[test]
+fn no_compaction_while_stepped_back() {
+  let dir = temp_dir("compact-stepped-back");
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  for txn in [new_foo(), set_foo(1.0), set_foo(2.0)] {
+    running.send(RunLoopMessage::Transaction(txn)).unwrap();
+    wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  }
+  running.send(RunLoopMessage::StepBack).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Time(1)));
+  running.compact().unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::String(_) | ClientMessage::Error(_))) {
+    ClientMessage::Error(err) => assert_eq!(err.id, 1317),
+    message => panic!("{:?}", message),
+  }
+  running.send(RunLoopMessage::Resume).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Resume));
+  running.compact().unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::String(_) | ClientMessage::Error(_))) {
+    ClientMessage::String(message) => assert_eq!(message, "Compacted 1 tables"),
+    message => panic!("{:?}", message),
+  }
+  stop(running);
+
+  // Nothing was undone in the log, so the restart sees the present
+  let running = runner(&dir).run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  assert!(is_foo(foo(&running), 2.0));
+  stop(running);
+}
+
+#[test]
+fn no_compaction_after_a_corrupt_load() {
+  let dir = temp_dir("compact-corrupt");
+  let path = dir.join("test.mdb").to_str().unwrap().to_string();
+  let persister = Persister::new(FileBackend::new(&path));
+  for txn in [new_foo(), set_foo(1.0), set_foo(2.0)] {
+    persister.send(txn).unwrap();
+  }
+  persister.close();
+  persister.wait();
+  // The next record starts a second segment
+  let persister = Persister::with_options(FileBackend::new(&path), PersisterOptions{max_segment_size: Some(1), ..PersisterOptions::default()});
+  persister.send(set_foo(3.0)).unwrap();
+  persister.close();
+  persister.wait();
+  // Flip a byte in the payload of the first segment's second record
+  let backend = FileBackend::new(&path);
+  let position = LogReader::new(&backend).unwrap().map(|item| item.unwrap()).find(|(_, change)| matches!(change, Change::Set(_))).unwrap().0;
+  let first_segment = persister::segment_path(&path, 0);
+  let mut bytes = std::fs::read(&first_segment).unwrap();
+  bytes[position.offset as usize + 8] ^= 0xff;
+  std::fs::write(&first_segment, &bytes).unwrap();
+
+  let mut runner = runner(&dir);
+  runner.compaction_threshold = Some(0);
+  let running = runner.run().unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::Error(_))) {
+    ClientMessage::Error(err) => assert_eq!(err.id, 1304),
+    _ => unreachable!(),
+  }
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  // Neither the threshold nor asking compacts it
+  running.send(RunLoopMessage::Transaction(set_foo(4.0))).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  running.compact().unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::String(_) | ClientMessage::Error(_))) {
+    ClientMessage::Error(err) => assert_eq!(err.id, 1319),
+    message => panic!("{:?}", message),
+  }
+  stop(running);
+  assert_eq!(std::fs::read(&first_segment).unwrap(), bytes);
+  assert!(!Path::new(&persister::snapshot_path(&path)).is_file());
+}
+
+fn robot_runner(dir: &Path) -> RunLoop {
+  let mut runner = runner(dir);
+  runner.persister_options.policy = PersistencePolicy::new().include("robot/*");
+  let running = runner.run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  running
+}
+
+// Loads a block that uses #robot/x, which is how the core learns its name
+fn load_robot_code(running: &RunLoop) {
+  running.send(RunLoopMessage::Code((1, MechCode::String("#other = #robot/x".to_string())))).unwrap();
+  wait_for(running, |m| matches!(m, ClientMessage::StepDone));
+}
+
+#[test]
+fn compaction_after_a_restart_keeps_replayed_tables() {
+  let dir = temp_dir("compact-restart");
+  let robot = hash_str("robot/x");
+  let running = robot_runner(&dir);
+  load_robot_code(&running);
+  running.send(RunLoopMessage::Transaction(vec![
+    Change::NewTable{table_id: robot, rows: 1, columns: 1},
+    Change::ColumnKind{table_id: robot, column_ix: 0, column_kind: ValueKind::F32},
+    Change::Set((robot, vec![(TableIndex::Index(1), TableIndex::Index(1), Value::F32(F32::new(7.0)))])),
+  ])).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  stop(running);
+
+  // #robot/x is replayed before any code names it, and compacting afterwards keeps it
+  let running = robot_runner(&dir);
+  load_robot_code(&running);
+  running.compact().unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::String(message) if message.starts_with("Compacted")) || matches!(m, ClientMessage::Error(_))) {
+    ClientMessage::String(message) => assert_eq!(message, "Compacted 1 tables"),
+    message => panic!("{:?}", message),
+  }
+  stop(running);
+
+  let running = robot_runner(&dir);
+  running.send(RunLoopMessage::GetValue((robot, TableIndex::Index(1), TableIndex::Index(1)))).unwrap();
+  match wait_for(&running, |m| matches!(m, ClientMessage::Value(_) | ClientMessage::Error(_))) {
+    ClientMessage::Value(value) => assert_eq!(value, Value::F32(F32::new(7.0))),
+    message => panic!("{:?}", message),
+  }
+  stop(running);
+}
+
+// The value a transaction from set_foo sets
+fn set_to(txn: &Transaction) -> Option<Value> {
+  match txn.first() {
+    Some(Change::Set((_, values))) => values.first().map(|(_, _, value)| value.clone()),
+    _ => None,
+  }
+}
+
+fn paused_runner(dir: &Path, limit: usize, overflow: QueueOverflow) -> RunLoop {
+  let mut runner = runner(dir);
+  runner.pause_queue_limit = limit;
+  runner.pause_queue_overflow = overflow;
+  let running = runner.run().unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Ready));
+  running.send(RunLoopMessage::Transaction(new_foo())).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  running.send(RunLoopMessage::Transaction(set_foo(0.0))).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::StepDone));
+  running.send(RunLoopMessage::Pause).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Pause));
+  running
+}
+
+fn queue(running: &RunLoop, txn: Transaction) -> (Option<Value>, usize) {
+  running.send(RunLoopMessage::Transaction(txn)).unwrap();
+  let mut dropped = None;
+  loop {
+    match wait_for(running, |m| matches!(m, ClientMessage::Queued(_) | ClientMessage::Dropped(_))) {
+      ClientMessage::Dropped(txn) => dropped = set_to(&txn),
+      ClientMessage::Queued(queued) => return (dropped, queued),
+      _ => unreachable!(),
+    }
+  }
+}
+
+#[test]
+fn queue_while_paused() {
+  let running = paused_runner(&temp_dir("queue-oldest"), 2, QueueOverflow::DropOldest);
+  assert_eq!(queue(&running, set_foo(1.0)), (None, 1));
+  assert_eq!(queue(&running, set_foo(2.0)), (None, 2));
+  assert_eq!(queue(&running, set_foo(3.0)), (Some(Value::F32(F32::new(1.0))), 2));
+  assert!(is_foo(foo(&running), 0.0));
+
+  // Stepping forward applies one queued transaction, and answers like a
+  // step through history
+  running.send(RunLoopMessage::StepForward).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Queued(1)));
+  wait_for(&running, |m| matches!(m, ClientMessage::Time(0)));
+  wait_for(&running, |m| matches!(m, ClientMessage::Done));
+  assert!(is_foo(foo(&running), 2.0));
+  running.send(RunLoopMessage::StepBack).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Time(1)));
+  running.send(RunLoopMessage::StepForward).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Time(0)));
+  wait_for(&running, |m| matches!(m, ClientMessage::Done));
+  assert!(is_foo(foo(&running), 2.0));
+
+  // Resuming applies the rest in order
+  running.send(RunLoopMessage::Resume).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Resume));
+  wait_for(&running, |m| matches!(m, ClientMessage::Queued(0)));
+  assert!(is_foo(foo(&running), 3.0));
+  stop(running);
+}
+
+#[test]
+fn drop_newest_while_paused() {
+  let running = paused_runner(&temp_dir("queue-newest"), 1, QueueOverflow::DropNewest);
+  assert_eq!(queue(&running, set_foo(1.0)), (None, 1));
+  assert_eq!(queue(&running, set_foo(2.0)), (Some(Value::F32(F32::new(2.0))), 1));
+  running.send(RunLoopMessage::Resume).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Queued(0)));
+  assert!(is_foo(foo(&running), 1.0));
+  stop(running);
+
+  // Without a queue, everything that arrives while paused is dropped
+  let running = paused_runner(&temp_dir("queue-none"), 0, QueueOverflow::DropOldest);
+  assert_eq!(queue(&running, set_foo(1.0)), (Some(Value::F32(F32::new(1.0))), 0));
+  running.send(RunLoopMessage::Resume).unwrap();
+  wait_for(&running, |m| matches!(m, ClientMessage::Resume));
+  assert!(is_foo(foo(&running), 0.0));
+  stop(running);
+}
+
+#[test]
+fn stopping_drops_the_queue() {
+  let running = paused_runner(&temp_dir("queue-stop"), 2, QueueOverflow::DropOldest);
+  assert_eq!(queue(&running, set_foo(1.0)), (None, 1));
+  assert_eq!(queue(&running, set_foo(2.0)), (None, 2));
+  running.send(RunLoopMessage::Stop).unwrap();
+  let mut dropped = vec![];
+  while let ClientMessage::Dropped(txn) = wait_for(&running, |m| matches!(m, ClientMessage::Dropped(_) | ClientMessage::Stop)) {
+    dropped.push(set_to(&txn).unwrap());
+  }
+  assert_eq!(dropped, vec![Value::F32(F32::new(1.0)), Value::F32(F32::new(2.0))]);
+  running.wait();
+}
@@ -0,0 +1,83 @@
+extern crate mech_program;
+extern crate mech_core;
+extern crate crossbeam_channel;
+use mech_program::*;
+use mech_core::*;
+
+fn new_program() -> Program {
+  let (outgoing, incoming) = crossbeam_channel::unbounded();
+  Program::new("test", 100, 1000, outgoing, incoming, "".to_string())
+}
+
+fn apply(program: &mut Program, txn: Transaction) {
+  let inverse = program.invert_transaction(&txn);
+  program.mech.process_transaction(&txn).unwrap();
+  program.record_transaction(txn, inverse);
+}
+
+fn new_foo() -> Transaction {
+  vec![
+    Change::NewTable{table_id: hash_str("foo"), rows: 1, columns: 1},
+    Change::ColumnKind{table_id: hash_str("foo"), column_ix: 0, column_kind: ValueKind::F32},
+  ]
+}
+
+fn set_foo(value: f32) -> Transaction {
+  vec![Change::Set((hash_str("foo"), vec![(TableIndex::Index(1), TableIndex::Index(1), Value::F32(F32::new(value)))]))]
+}
+
+fn foo(program: &Program) -> Value {
+  program.mech.get_table_by_id(hash_str("foo")).unwrap().borrow().get(&TableIndex::Index(1), &TableIndex::Index(1)).unwrap()
+}
+
+#[test]
+fn step_back_and_forward() {
+  let mut program = new_program();
+  apply(&mut program, new_foo());
+  apply(&mut program, set_foo(1.0));
+  apply(&mut program, set_foo(2.0));
+
+  assert_eq!(program.step_back_one().unwrap(), 1);
+  assert_eq!(foo(&program), Value::F32(F32::new(1.0)));
+  assert_eq!(program.step_back_one().unwrap(), 2);
+  assert_eq!(program.step_forward_one().unwrap(), 1);
+  assert_eq!(foo(&program), Value::F32(F32::new(1.0)));
+  assert_eq!(program.step_forward_one().unwrap(), 0);
+  assert_eq!(foo(&program), Value::F32(F32::new(2.0)));
+  // Nothing left to step forward over
+  assert_eq!(program.step_forward_one().unwrap(), 0);
+}
+
+#[test]
+fn history_is_bounded() {
+  let mut program = new_program();
+  program.history_limit = 2;
+  apply(&mut program, new_foo());
+  for i in 0..5 {
+    apply(&mut program, set_foo(i as f32));
+  }
+  assert_eq!(program.history.len(), 2);
+  assert_eq!(program.step_back_one().unwrap(), 1);
+  assert_eq!(program.step_back_one().unwrap(), 2);
+  assert_eq!(program.step_back_one().unwrap(), 2);
+}
+
+#[test]
+fn step_back_over_a_column_change() {
+  let mut program = new_program();
+  apply(&mut program, new_foo());
+  apply(&mut program, set_foo(1.0));
+  let mut txn = vec![
+    Change::ColumnKind{table_id: hash_str("foo"), column_ix: 0, column_kind: ValueKind::F32},
+    Change::ColumnAlias{table_id: hash_str("foo"), column_ix: 0, column_alias: hash_str("x")},
+  ];
+  txn.append(&mut set_foo(2.0));
+  apply(&mut program, txn);
+
+  assert_eq!(program.step_back_one().unwrap(), 1);
+  assert_eq!(foo(&program), Value::F32(F32::new(1.0)));
+  assert_eq!(program.step_back_one().unwrap(), 2);
+  assert_eq!(program.step_forward_one().unwrap(), 1);
+  assert_eq!(program.step_forward_one().unwrap(), 0);
+  assert_eq!(foo(&program), Value::F32(F32::new(2.0)));
+}